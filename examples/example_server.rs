@@ -1,13 +1,19 @@
 use std::sync::Arc;
 
 use async_trait::async_trait;
+use futures::StreamExt;
 use http::Response;
 use hyper::Body;
 
-use hyper_fast::server::{ApiError, HttpResponse, HttpRoute, Service};
+use hyper_fast::server::{ApiError, HttpRequest, HttpResponse, HttpResult, HttpRoute, Middleware, Next, PathParams, Router};
+use hyper_fast::server::{Service, SseEvent};
 use hyper_fast::server::{ServiceBuilder, ServiceDaemon, start_http_server};
 #[cfg(feature = "settings")]
 use hyper_fast::server::utils::load_config;
+#[cfg(feature = "tower")]
+use hyper_fast::server::TowerMount;
+#[cfg(feature = "websocket")]
+use hyper_fast::server::WebSocket;
 #[cfg(any(feature = "access_log", feature = "metrics"))]
 use hyper_fast::server::utils::setup_logging;
 
@@ -16,7 +22,7 @@ async fn main() -> Result<(), anyhow::Error> {
     #[cfg(feature = "settings")]
     load_config("examples/config", "dev")?;
 
-    #[cfg(any(feature = "access_log"))]
+    #[cfg(feature = "access_log")]
     setup_logging("examples/config/log4rs.yml")?;
 
     start_http_server("127.0.0.1:6464", ExampleServiceBuilder {}).await
@@ -24,6 +30,19 @@ async fn main() -> Result<(), anyhow::Error> {
 
 pub struct ExampleService {
     // any service level properties
+    users: Router<Users>,
+    middlewares: Vec<Box<dyn Middleware>>,
+    #[cfg(feature = "tower")]
+    legacy: TowerMount<LegacyService>,
+}
+
+// any tower service, e.g. an axum router or a tower-http `ServeDir`
+#[cfg(feature = "tower")]
+#[derive(Clone)]
+pub struct LegacyService;
+
+pub struct Users {
+    names: Vec<&'static str>,
 }
 
 pub struct ExampleServiceDaemon {}
@@ -42,7 +61,19 @@ impl ServiceDaemon<ExampleService> for ExampleServiceDaemon {
 #[async_trait]
 impl ServiceBuilder<ExampleService, ExampleServiceDaemon> for ExampleServiceBuilder {
     async fn build(self) -> anyhow::Result<(ExampleService, Option<ExampleServiceDaemon>)> {
-        let service = ExampleService {};
+        let users = Users {
+            names: vec!["alice", "bob"],
+        };
+        let users = Router::new(users)
+            .get("/api/users/:id", |users, _body, route, params| Box::pin(users.get_user(route, params)))
+            .get("/api/files/*path", |_, _body, route, params| Box::pin(get_file(route, params)));
+
+        let service = ExampleService {
+            users,
+            middlewares: vec![Box::new(AllowAnyOrigin)],
+            #[cfg(feature = "tower")]
+            legacy: TowerMount::new("/api/legacy", LegacyService),
+        };
 
         Ok((service, None))
     }
@@ -52,7 +83,7 @@ impl ServiceBuilder<ExampleService, ExampleServiceDaemon> for ExampleServiceBuil
 impl Service for ExampleService {
     async fn api_handler<'a>(
         &'a self,
-        _: Body,
+        body: Body,
         route: &HttpRoute<'a>,
         path: &[&str],
     ) -> Result<Response<Body>, ApiError> {
@@ -60,14 +91,136 @@ impl Service for ExampleService {
             ["test"] if matches!(route.method, &http::Method::GET) => {
                 self.get_test(route).await
             }
-            _ => HttpResponse::not_found(route.path),
+            ["echo"] if matches!(route.method, &http::Method::POST) => {
+                self.post_echo(body, route).await
+            }
+            ["numbers"] if matches!(route.method, &http::Method::GET) => {
+                HttpResponse::json_array_stream(route, numbers())
+            }
+            ["numbers", "ndjson"] if matches!(route.method, &http::Method::GET) => {
+                HttpResponse::ndjson_stream(route, numbers())
+            }
+            ["events"] if matches!(route.method, &http::Method::GET) => {
+                let last_event_id = route.last_event_id.and_then(|id| id.parse::<u64>().ok()).unwrap_or(0);
+                HttpResponse::sse(route, ticks(last_event_id + 1))
+            }
+            #[cfg(feature = "websocket")]
+            ["ws"] => HttpRequest::upgrade_websocket(route, echo_messages),
+            #[cfg(feature = "tower")]
+            ["legacy", ..] => self.legacy.handle(body, route).await,
+            #[cfg(feature = "protobuf")]
+            ["greet"] if matches!(route.method, &http::Method::POST) => {
+                self.post_greet(body, route).await
+            }
+            _ => self.users.handle(body, route).await,
         }
     }
+
+    fn middlewares(&self) -> &[Box<dyn Middleware>] {
+        &self.middlewares
+    }
 }
 
 impl ExampleService {
     pub async fn get_test(&self, route: &HttpRoute<'_>) -> Result<Response<Body>, ApiError> {
         HttpResponse::string(route, "GET::/api/test - test passed".to_string())
     }
+
+    pub async fn post_echo(&self, body: Body, route: &HttpRoute<'_>) -> Result<Response<Body>, ApiError> {
+        let value: serde_json::Value = HttpRequest::value(route, body).await?;
+
+        HttpResponse::binary_or_json(route, &value)
+    }
+
+    #[cfg(feature = "protobuf")]
+    pub async fn post_greet(&self, body: Body, route: &HttpRoute<'_>) -> Result<Response<Body>, ApiError> {
+        let greeting: Greeting = HttpRequest::proto_or_json(route, body).await?;
+
+        let greeting = Greeting {
+            message: format!("Hello, {}", greeting.message),
+        };
+
+        HttpResponse::proto_or_json(route, &greeting)
+    }
+}
+
+// lets browser apps on any origin call the API
+pub struct AllowAnyOrigin;
+
+#[async_trait]
+impl Middleware for AllowAnyOrigin {
+    async fn handle<'a>(&'a self, body: Body, route: &HttpRoute<'a>, next: Next<'a>) -> HttpResult {
+        let mut response = next.run(body, route).await;
+        response
+            .headers_mut()
+            .insert(http::header::ACCESS_CONTROL_ALLOW_ORIGIN, http::HeaderValue::from_static("*"));
+
+        Ok(response)
+    }
 }
 
+impl Users {
+    pub async fn get_user(&self, route: &HttpRoute<'_>, params: PathParams<'_>) -> Result<Response<Body>, ApiError> {
+        let id: usize = params.get("id")?;
+
+        match self.names.get(id) {
+            Some(name) => HttpResponse::string(route, name.to_string()),
+            None => HttpResponse::not_found(route.path),
+        }
+    }
+}
+
+pub async fn get_file(route: &HttpRoute<'_>, params: PathParams<'_>) -> Result<Response<Body>, ApiError> {
+    let path: String = params.get("path")?;
+
+    HttpResponse::string(route, format!("GET::/api/files - {}", path))
+}
+
+#[cfg(feature = "tower")]
+impl hyper::service::Service<http::Request<Body>> for LegacyService {
+    type Response = Response<Body>;
+    type Error = std::convert::Infallible;
+    type Future = futures::future::Ready<Result<Response<Body>, std::convert::Infallible>>;
+
+    fn poll_ready(&mut self, _cx: &mut std::task::Context<'_>) -> std::task::Poll<Result<(), Self::Error>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: http::Request<Body>) -> Self::Future {
+        let body = Body::from(format!("{} {} - served by a tower service", req.method(), req.uri()));
+
+        futures::future::ready(Ok(Response::new(body)))
+    }
+}
+
+// a result set produced lazily, as it would be read from a database cursor
+fn numbers() -> impl futures::Stream<Item=Result<serde_json::Value, std::io::Error>> {
+    futures::stream::iter(1..=10_000).map(|n| Ok(serde_json::json!({ "n": n })))
+}
+
+// one event a second, resuming after the last event the client saw
+fn ticks(from: u64) -> impl futures::Stream<Item=Result<SseEvent, std::io::Error>> {
+    futures::stream::iter(from..).then(|id| async move {
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+        Ok(SseEvent::data(format!("tick {}", id)).id(id.to_string()).event("tick"))
+    })
+}
+
+// echoes text and binary messages back until the client closes the socket
+#[cfg(feature = "websocket")]
+async fn echo_messages(mut socket: WebSocket) {
+    use futures::SinkExt;
+
+    while let Some(Ok(message)) = socket.next().await {
+        if (message.is_text() || message.is_binary()) && socket.send(message).await.is_err() {
+            break;
+        }
+    }
+}
+
+#[cfg(feature = "protobuf")]
+#[derive(Clone, PartialEq, prost::Message, serde::Serialize, serde::Deserialize)]
+pub struct Greeting {
+    #[prost(string, tag = "1")]
+    pub message: String,
+}
@@ -0,0 +1,210 @@
+use anyhow::Context;
+use bytes::Buf;
+use serde::{Deserialize, Serialize};
+
+use super::negotiation::{media_type_quality, parse_quality_list};
+
+/// Body formats read by `HttpRequest::value` and written by `HttpResponse::binary_or_json`, each but JSON behind its own
+/// feature. Bincode is only written, as decoding it from untrusted clients can allocate without bound.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Format {
+    Json,
+    #[cfg(feature = "msgpack")]
+    MessagePack,
+    #[cfg(feature = "cbor")]
+    Cbor,
+    #[cfg(feature = "form")]
+    Form,
+    #[cfg(feature = "bincode")]
+    Bincode,
+}
+
+// in order of server preference
+pub(crate) const FORMATS: &[Format] = &[
+    Format::Json,
+    #[cfg(feature = "msgpack")]
+    Format::MessagePack,
+    #[cfg(feature = "cbor")]
+    Format::Cbor,
+    #[cfg(feature = "form")]
+    Format::Form,
+];
+
+// in order of server preference, forms being a request only format and bincode a response only one
+const RESPONSE_FORMATS: &[Format] = &[
+    Format::Json,
+    #[cfg(feature = "msgpack")]
+    Format::MessagePack,
+    #[cfg(feature = "cbor")]
+    Format::Cbor,
+    #[cfg(feature = "bincode")]
+    Format::Bincode,
+];
+
+impl Format {
+    /// Media types this format is known by, the first one being the `Content-Type` it is written with.
+    pub(crate) fn media_types(&self) -> &'static [&'static str] {
+        match self {
+            Format::Json => &["application/json", "text/json"],
+            #[cfg(feature = "msgpack")]
+            Format::MessagePack => &["application/msgpack", "application/x-msgpack", "application/vnd.msgpack"],
+            #[cfg(feature = "cbor")]
+            Format::Cbor => &["application/cbor"],
+            #[cfg(feature = "form")]
+            Format::Form => &["application/x-www-form-urlencoded"],
+            #[cfg(feature = "bincode")]
+            Format::Bincode => &["application/x-bincode", "application/bincode"],
+        }
+    }
+
+    /// Finds the format of a `Content-Type` header value, ignoring its parameters. Structured syntax suffixes such as
+    /// `application/problem+json` map to their base format.
+    pub(crate) fn from_content_type(content_type: &str) -> Option<Format> {
+        let media_type = content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+
+        FORMATS.iter().copied().find(|format| {
+            format.media_types().contains(&media_type.as_str())
+                || format.suffix().is_some_and(|suffix| media_type.starts_with("application/") && media_type.ends_with(suffix))
+        })
+    }
+
+    fn suffix(&self) -> Option<&'static str> {
+        match self {
+            Format::Json => Some("+json"),
+            #[cfg(feature = "cbor")]
+            Format::Cbor => Some("+cbor"),
+            #[allow(unreachable_patterns)]
+            _ => None,
+        }
+    }
+
+    pub(crate) fn decode<T>(&self, body: impl Buf) -> anyhow::Result<T>
+        where
+            T: for<'de> Deserialize<'de>,
+    {
+        match self {
+            Format::Json => serde_json::from_reader(body.reader()).with_context(|| "Error in decoding JSON body"),
+            #[cfg(feature = "msgpack")]
+            Format::MessagePack => rmp_serde::from_read(body.reader()).with_context(|| "Error in decoding MessagePack body"),
+            #[cfg(feature = "cbor")]
+            Format::Cbor => ciborium::de::from_reader(body.reader()).with_context(|| "Error in decoding CBOR body"),
+            #[cfg(feature = "form")]
+            Format::Form => serde_urlencoded::from_reader(body.reader()).with_context(|| "Error in decoding form body"),
+            #[cfg(feature = "bincode")]
+            Format::Bincode => Err(anyhow::anyhow!("bincode bodies are not accepted")),
+        }
+    }
+
+    pub(crate) fn encode<S>(&self, body: &S) -> anyhow::Result<Vec<u8>>
+        where
+            S: Serialize,
+    {
+        match self {
+            Format::Json => serde_json::to_vec(body).with_context(|| "Error in serialising to JSON"),
+            #[cfg(feature = "msgpack")]
+            Format::MessagePack => rmp_serde::to_vec_named(body).with_context(|| "Error in serialising to MessagePack"),
+            #[cfg(feature = "cbor")]
+            Format::Cbor => {
+                let mut buffer = Vec::new();
+                ciborium::ser::into_writer(body, &mut buffer).with_context(|| "Error in serialising to CBOR")?;
+                Ok(buffer)
+            }
+            #[cfg(feature = "form")]
+            Format::Form => serde_urlencoded::to_string(body).map(String::into_bytes).with_context(|| "Error in serialising to form"),
+            #[cfg(feature = "bincode")]
+            Format::Bincode => bincode::serialize(body).with_context(|| "Error in serialising to bincode"),
+        }
+    }
+
+    /// Picks the response format for an `Accept` header, JSON when the header is absent. `None` means none of the
+    /// response formats is acceptable.
+    pub(crate) fn negotiate(accept: Option<&str>) -> Option<Format> {
+        let accept = match accept {
+            Some(accept) => parse_quality_list(accept),
+            None => return Some(Format::Json),
+        };
+
+        let mut best: Option<(Format, u16)> = None;
+        for format in RESPONSE_FORMATS {
+            let quality = format
+                .media_types()
+                .iter()
+                .filter_map(|media_type| media_type_quality(&accept, media_type))
+                .max()
+                .unwrap_or(0);
+
+            if quality > 0 && best.is_none_or(|(_, best_quality)| quality > best_quality) {
+                best = Some((*format, quality));
+            }
+        }
+
+        best.map(|(format, _)| format)
+    }
+}
+
+/// `Content-Type` of protobuf bodies.
+#[cfg(feature = "protobuf")]
+pub(crate) const PROTOBUF_CONTENT_TYPE: &str = "application/x-protobuf";
+
+// `proto/bytes` is what `HttpResponse::proto_binary` used to send
+#[cfg(feature = "protobuf")]
+const PROTOBUF_MEDIA_TYPES: &[&str] = &[PROTOBUF_CONTENT_TYPE, "application/protobuf", "application/vnd.google.protobuf", "proto/bytes"];
+
+#[cfg(feature = "protobuf")]
+pub(crate) fn is_protobuf(content_type: &str) -> bool {
+    let media_type = content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+
+    PROTOBUF_MEDIA_TYPES.contains(&media_type.as_str())
+}
+
+#[cfg(feature = "protobuf")]
+pub(crate) enum ProtobufFormat {
+    Protobuf,
+    Json,
+}
+
+/// Chooses between protobuf and its JSON transcoding for an `Accept` header, protobuf winning ties and the absence of
+/// the header. `None` means neither is acceptable.
+#[cfg(feature = "protobuf")]
+pub(crate) fn negotiate_protobuf(accept: Option<&str>) -> Option<ProtobufFormat> {
+    let accept = match accept {
+        Some(accept) => parse_quality_list(accept),
+        None => return Some(ProtobufFormat::Protobuf),
+    };
+
+    let quality_of = |media_types: &[&str]| {
+        media_types
+            .iter()
+            .filter_map(|media_type| media_type_quality(&accept, media_type))
+            .max()
+            .unwrap_or(0)
+    };
+    let protobuf_quality = quality_of(PROTOBUF_MEDIA_TYPES);
+    let json_quality = quality_of(Format::Json.media_types());
+
+    if protobuf_quality > 0 && protobuf_quality >= json_quality {
+        Some(ProtobufFormat::Protobuf)
+    } else if json_quality > 0 {
+        Some(ProtobufFormat::Json)
+    } else {
+        None
+    }
+}
+
+/// Canonical media types of the enabled formats, as listed in `415 Unsupported Media Type` responses.
+pub(crate) fn accepted_content_types() -> String {
+    FORMATS
+        .iter()
+        .map(|format| format.media_types()[0])
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Canonical media types of the enabled response formats, as listed in `406 Not Acceptable` responses.
+pub(crate) fn available_content_types() -> String {
+    RESPONSE_FORMATS
+        .iter()
+        .map(|format| format.media_types()[0])
+        .collect::<Vec<_>>()
+        .join(", ")
+}
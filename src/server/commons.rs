@@ -1,5 +1,3 @@
-use std::iter::repeat;
-
 use http::HeaderValue;
 use libc::{c_char, c_int, size_t};
 
@@ -11,6 +9,8 @@ lazy_static! {
 pub const BR_CONTENT_ENCODING: &[u8] = b"br";
 pub const DEFLATE_CONTENT_ENCODING: &[u8] = b"deflate";
 pub const GZIP_CONTENT_ENCODING: &[u8] = b"gzip";
+#[cfg(feature = "zstd")]
+pub const ZSTD_CONTENT_ENCODING: &[u8] = b"zstd";
 
 pub fn hostname_header() -> HeaderValue {
     HeaderValue::from_static(get_hostname())
@@ -32,7 +32,7 @@ extern "C" {
 pub fn hostname() -> String {
     // Create a buffer for the hostname to be copied into
     let buffer_len: usize = 255;
-    let mut buffer: Vec<u8> = repeat(0).take(buffer_len).collect();
+    let mut buffer: Vec<u8> = vec![0; buffer_len];
 
     let error = unsafe { gethostname(buffer.as_mut_ptr() as *mut c_char, buffer_len as size_t) };
 
@@ -46,7 +46,7 @@ pub fn hostname() -> String {
 
     // Create an owned string from the buffer, transforming UTF-8 errors into IO errors
     match String::from_utf8(buffer) {
-        Ok(hostname) => return hostname,
+        Ok(hostname) => hostname,
         Err(err) => {
             let err_msg = format!("Failed to convert to String {}", err);
             panic!("{}", err_msg);
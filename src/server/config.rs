@@ -0,0 +1,389 @@
+use std::time::Duration;
+
+#[cfg(feature = "settings")]
+use serde::Deserialize;
+
+#[cfg(feature = "tls")]
+use super::tls::TlsConfig;
+
+// hyper does not accept a smaller HTTP/1 read buffer
+const MIN_HEADER_SIZE: usize = 8192;
+const DEFAULT_MAX_BODY_SIZE: usize = 1_048_576;
+const DEFAULT_MAX_DECOMPRESSED_SIZE: usize = 16 * 1_048_576;
+const DEFAULT_MAX_DECOMPRESSION_RATIO: usize = 100;
+const DEFAULT_SSE_KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+#[cfg(feature = "websocket")]
+const DEFAULT_MAX_WEBSOCKET_MESSAGE_SIZE: usize = 16 * 1_048_576;
+#[cfg(feature = "websocket")]
+const DEFAULT_MAX_WEBSOCKET_FRAME_SIZE: usize = 4 * 1_048_576;
+
+/// Which HTTP protocols the server speaks on a connection.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "settings", derive(Deserialize), serde(rename_all = "lowercase"))]
+pub enum HttpProtocol {
+    /// HTTP/1.1, plus HTTP/2 via prior-knowledge h2c or ALPN `h2` over TLS.
+    #[default]
+    Auto,
+    /// HTTP/1.1 only.
+    Http1,
+    /// HTTP/2 only, i.e. prior-knowledge h2c on plain TCP and ALPN `h2` over TLS.
+    Http2,
+}
+
+impl HttpProtocol {
+    #[cfg(feature = "tls")]
+    pub(crate) fn alpn_protocols(&self) -> Vec<Vec<u8>> {
+        match self {
+            HttpProtocol::Auto => vec![b"h2".to_vec(), b"http/1.1".to_vec()],
+            HttpProtocol::Http1 => vec![b"http/1.1".to_vec()],
+            HttpProtocol::Http2 => vec![b"h2".to_vec()],
+        }
+    }
+}
+
+/// HTTP/2 connection settings. `None` values keep hyper's defaults.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "settings", derive(Deserialize), serde(default))]
+pub struct Http2Config {
+    pub protocol: HttpProtocol,
+    pub max_concurrent_streams: Option<u32>,
+    pub initial_stream_window_size: Option<u32>,
+    pub initial_connection_window_size: Option<u32>,
+    pub adaptive_window: bool,
+    pub max_frame_size: Option<u32>,
+    #[cfg_attr(feature = "settings", serde(with = "humantime_serde"))]
+    pub keep_alive_interval: Option<Duration>,
+    #[cfg_attr(feature = "settings", serde(with = "humantime_serde"))]
+    pub keep_alive_timeout: Duration,
+}
+
+impl Default for Http2Config {
+    fn default() -> Self {
+        Http2Config {
+            protocol: HttpProtocol::Auto,
+            max_concurrent_streams: None,
+            initial_stream_window_size: None,
+            initial_connection_window_size: None,
+            adaptive_window: false,
+            max_frame_size: None,
+            keep_alive_interval: None,
+            keep_alive_timeout: Duration::from_secs(20),
+        }
+    }
+}
+
+/// Built-in routes served next to the application's `/api` routes.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "settings", derive(Deserialize), serde(default))]
+pub struct BuiltinRoutes {
+    /// `GET /`
+    pub index: bool,
+    /// `GET /health`
+    pub health: bool,
+    /// `/oor`
+    pub oor: bool,
+    /// `/metrics/json` and `/metrics/prometheus`
+    pub metrics: bool,
+}
+
+impl Default for BuiltinRoutes {
+    fn default() -> Self {
+        BuiltinRoutes {
+            index: true,
+            health: true,
+            oor: true,
+            metrics: true,
+        }
+    }
+}
+
+/// Response compression policy.
+///
+/// Content types are matched on their media type, either exactly (`application/json`) or by top level type (`image/*`).
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "settings", derive(Deserialize), serde(default))]
+pub struct CompressionConfig {
+    pub enabled: bool,
+    /// Bodies of a known size below this many bytes are sent as is.
+    pub min_size: usize,
+    /// When not empty, only these content types are compressed.
+    pub content_types: Vec<String>,
+    /// Content types that are never compressed, typically because they are compressed already.
+    pub skip_content_types: Vec<String>,
+    /// brotli quality, 0 (fastest) to 11 (smallest). `None` uses the library default.
+    pub br_level: Option<i32>,
+    /// gzip level, 0 to 9. `None` uses the library default.
+    pub gzip_level: Option<i32>,
+    /// deflate level, 0 to 9. `None` uses the library default.
+    pub deflate_level: Option<i32>,
+    /// zstd compression level, 1 (fastest) to 22 (smallest). `None` uses the zstd default of 3. Needs the `zstd` feature.
+    pub zstd_level: Option<i32>,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        CompressionConfig {
+            enabled: true,
+            min_size: 1024,
+            content_types: vec![],
+            skip_content_types: [
+                "image/*",
+                "video/*",
+                "audio/*",
+                "font/woff",
+                "font/woff2",
+                "application/zip",
+                "application/gzip",
+                "application/zstd",
+                "application/octet-stream",
+                "application/x-protobuf",
+                "application/protobuf",
+                "application/vnd.google.protobuf",
+                "proto/bytes",
+            ]
+            .iter()
+            .map(|content_type| content_type.to_string())
+            .collect(),
+            br_level: None,
+            gzip_level: None,
+            deflate_level: None,
+            zstd_level: None,
+        }
+    }
+}
+
+impl CompressionConfig {
+    pub(crate) fn is_compressible_content_type(&self, content_type: Option<&str>) -> bool {
+        let media_type = match content_type {
+            Some(content_type) => content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase(),
+            // nothing to match an allow list against
+            None => return self.content_types.is_empty(),
+        };
+
+        let matches = |pattern: &String| match pattern.strip_suffix("/*") {
+            Some(top_level_type) => media_type.split('/').next() == Some(top_level_type),
+            None => pattern.eq_ignore_ascii_case(&media_type),
+        };
+
+        (self.content_types.is_empty() || self.content_types.iter().any(matches)) && !self.skip_content_types.iter().any(matches)
+    }
+}
+
+/// Limits and extensions applied to WebSocket connections. `None` removes a limit.
+#[cfg(feature = "websocket")]
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "settings", derive(Deserialize), serde(default))]
+pub struct WebSocketConfig {
+    /// Upper bound on the size of a message, in bytes, once its frames are reassembled.
+    pub max_message_size: Option<usize>,
+    /// Upper bound on the size of a single frame, in bytes.
+    pub max_frame_size: Option<usize>,
+    /// Compresses messages with the `permessage-deflate` extension (RFC 7692) for clients offering it.
+    pub permessage_deflate: bool,
+}
+
+#[cfg(feature = "websocket")]
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        WebSocketConfig {
+            max_message_size: Some(DEFAULT_MAX_WEBSOCKET_MESSAGE_SIZE),
+            max_frame_size: Some(DEFAULT_MAX_WEBSOCKET_FRAME_SIZE),
+            permessage_deflate: false,
+        }
+    }
+}
+
+/// Request timeout for the paths starting with `path_prefix`, e.g. `/api/reports`, overriding `request_timeout`.
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "settings", derive(Deserialize), serde(default))]
+pub struct RouteTimeout {
+    pub path_prefix: String,
+    /// `None` lets requests under the prefix run without a deadline.
+    #[cfg_attr(feature = "settings", serde(with = "humantime_serde"))]
+    pub timeout: Option<Duration>,
+}
+
+/// Server level configuration, either built in code or read from the `server` section of the global settings.
+///
+/// ```yaml
+/// server:
+///   addrs: ["0.0.0.0:6464"]
+///   header_read_timeout: 30s
+///   keep_alive_timeout: 75s
+///   max_connections: 10000
+///   shutdown_timeout: 30s
+///   request_timeout: 30s
+///   route_timeouts:
+///     - path_prefix: /api/reports
+///       timeout: 5m
+///   builtin_routes:
+///     oor: false
+/// ```
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "settings", derive(Deserialize), serde(default))]
+pub struct ServerConfig {
+    /// Addresses to listen on, every one of them serves the same routes.
+    pub addrs: Vec<String>,
+    /// Time allowed for a client to send the request headers (HTTP/1 only).
+    #[cfg_attr(feature = "settings", serde(with = "humantime_serde"))]
+    pub header_read_timeout: Option<Duration>,
+    /// Whether HTTP/1 connections are kept alive between requests.
+    pub keep_alive: bool,
+    /// Connections with no request in flight for this long are closed, a request being in flight until its response
+    /// body is sent. Other traffic, e.g. HTTP/2 pings, does not keep a connection open.
+    #[cfg_attr(feature = "settings", serde(with = "humantime_serde"))]
+    pub keep_alive_timeout: Option<Duration>,
+    /// Upper bound on the size of request headers, in bytes. Must be at least 8192.
+    pub max_header_size: Option<usize>,
+    /// Upper bound on concurrently open connections, across all addresses. Further connections wait to be accepted.
+    pub max_connections: Option<usize>,
+    /// Upper bound on the size of a request body as received on the wire, in bytes. Handlers can override it per route
+    /// with `HttpRequest::bytes_with_limit`. Defaults to 1 MiB, or to the `json_payload_limit` setting when loaded from a
+    /// `server` section that does not set it.
+    pub max_body_size: usize,
+    /// Upper bound on the size of a compressed request body once decoded, in bytes.
+    pub max_decompressed_size: usize,
+    /// Upper bound on the ratio between the decoded size of a compressed request body and its size on the wire.
+    pub max_decompression_ratio: usize,
+    /// On shutdown, time given to in-flight connections to drain: `start_http_server` returns once they have closed, and
+    /// the process exits if they are still open when it elapses. `None` exits immediately.
+    #[cfg_attr(feature = "settings", serde(with = "humantime_serde"))]
+    pub shutdown_timeout: Option<Duration>,
+    /// Time allowed for the middlewares and the service to answer a request under `/api`, from its arrival, after
+    /// which it is cancelled and gets `504 Gateway Timeout`. Middlewares see the `504` of a service running out of time.
+    /// Streamed bodies are not limited once the response has started. `None` waits forever.
+    #[cfg_attr(feature = "settings", serde(with = "humantime_serde"))]
+    pub request_timeout: Option<Duration>,
+    /// Overrides of `request_timeout` by path prefix, the longest matching prefix winning.
+    pub route_timeouts: Vec<RouteTimeout>,
+    /// Whether error responses show the whole error chain of `ApiError::BadRequest` and
+    /// `ApiError::InternalServerError`. Keep it off where clients are not trusted.
+    pub expose_error_details: bool,
+    /// Server-Sent Events streams send a keep-alive comment after this long without an event. `None` disables them.
+    #[cfg_attr(feature = "settings", serde(with = "humantime_serde"))]
+    pub sse_keep_alive_interval: Option<Duration>,
+    pub builtin_routes: BuiltinRoutes,
+    pub compression: CompressionConfig,
+    pub http2: Http2Config,
+    #[cfg(feature = "websocket")]
+    pub websocket: WebSocketConfig,
+    #[cfg(feature = "tls")]
+    pub tls: Option<TlsConfig>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            addrs: vec![],
+            header_read_timeout: None,
+            keep_alive: true,
+            keep_alive_timeout: None,
+            max_header_size: None,
+            max_connections: None,
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+            max_decompressed_size: DEFAULT_MAX_DECOMPRESSED_SIZE,
+            max_decompression_ratio: DEFAULT_MAX_DECOMPRESSION_RATIO,
+            shutdown_timeout: None,
+            request_timeout: None,
+            route_timeouts: vec![],
+            expose_error_details: false,
+            sse_keep_alive_interval: Some(DEFAULT_SSE_KEEP_ALIVE_INTERVAL),
+            builtin_routes: BuiltinRoutes::default(),
+            compression: CompressionConfig::default(),
+            http2: Http2Config::default(),
+            #[cfg(feature = "websocket")]
+            websocket: WebSocketConfig::default(),
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn new(addr: &str) -> ServerConfig {
+        ServerConfig {
+            addrs: vec![addr.to_string()],
+            ..ServerConfig::default()
+        }
+    }
+
+    /// Reads the `server` section of the global settings, falling back to defaults when it is absent.
+    pub fn load() -> anyhow::Result<ServerConfig> {
+        #[cfg(feature = "settings")]
+        return crate::server::settings::server_config();
+
+        #[cfg(not(feature = "settings"))]
+        Ok(ServerConfig::default())
+    }
+
+    pub(crate) fn validate(&self) -> anyhow::Result<()> {
+        if self.addrs.is_empty() {
+            anyhow::bail!("No address configured for the server to listen on");
+        }
+
+        if let Some(max_header_size) = self.max_header_size {
+            if max_header_size < MIN_HEADER_SIZE {
+                anyhow::bail!("max_header_size: {} is below the minimum of {} bytes", max_header_size, MIN_HEADER_SIZE);
+            }
+        }
+
+        if self.max_decompression_ratio == 0 {
+            anyhow::bail!("max_decompression_ratio must be greater than 0");
+        }
+
+        if self.sse_keep_alive_interval == Some(Duration::ZERO) {
+            anyhow::bail!("sse_keep_alive_interval must be greater than 0");
+        }
+
+        if self.max_connections == Some(0) {
+            anyhow::bail!("max_connections must be greater than 0");
+        }
+
+        if self.request_timeout == Some(Duration::ZERO) {
+            anyhow::bail!("request_timeout must be greater than 0");
+        }
+
+        for route_timeout in &self.route_timeouts {
+            if !route_timeout.path_prefix.starts_with('/') {
+                anyhow::bail!("route_timeouts path_prefix: {:?} must start with '/'", route_timeout.path_prefix);
+            }
+            if route_timeout.timeout == Some(Duration::ZERO) {
+                anyhow::bail!("route_timeouts timeout for {:?} must be greater than 0", route_timeout.path_prefix);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Timeout of a request to `path`, from the longest `route_timeouts` prefix matching it or `request_timeout`.
+    pub(crate) fn request_timeout_for(&self, path: &str) -> Option<Duration> {
+        self.route_timeouts
+            .iter()
+            .filter(|route_timeout| is_path_prefix(&route_timeout.path_prefix, path))
+            .max_by_key(|route_timeout| route_timeout.path_prefix.len())
+            .map_or(self.request_timeout, |route_timeout| route_timeout.timeout)
+    }
+}
+
+// whole segments only, `/api/report` is not a prefix of `/api/reports`
+fn is_path_prefix(prefix: &str, path: &str) -> bool {
+    let prefix = prefix.trim_end_matches('/');
+
+    path.strip_prefix(prefix).is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+/// Keeps the existing `start_http_server("127.0.0.1:6464", ..)` call working: the address replaces the configured ones,
+/// everything else comes from the global settings. A `server` section that fails to load is an error, rather than
+/// silently serving with defaults.
+impl TryFrom<&str> for ServerConfig {
+    type Error = anyhow::Error;
+
+    fn try_from(addr: &str) -> anyhow::Result<Self> {
+        let config = ServerConfig::load()?;
+
+        Ok(ServerConfig {
+            addrs: vec![addr.to_string()],
+            ..config
+        })
+    }
+}
@@ -0,0 +1,239 @@
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures::task::AtomicWaker;
+use http::HeaderMap;
+use hyper::body::{HttpBody, SizeHint};
+use hyper::server::accept::Accept;
+use hyper::Body;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::{Instant, Sleep};
+use tokio_util::sync::PollSemaphore;
+
+use super::http_server::RemoteAddr;
+
+/// Wraps a listener to bound the number of open connections and to close idle keep-alive connections.
+///
+/// A permit is held for every accepted socket, so under TLS the connections still in their handshake count too.
+pub(crate) struct ServerIncoming<I> {
+    incoming: I,
+    connection_limit: Option<PollSemaphore>,
+    permit: Option<OwnedSemaphorePermit>,
+    keep_alive_timeout: Option<Duration>,
+}
+
+impl<I> ServerIncoming<I> {
+    pub(crate) fn new(incoming: I, connection_limit: Option<Arc<Semaphore>>, keep_alive_timeout: Option<Duration>) -> ServerIncoming<I> {
+        ServerIncoming {
+            incoming,
+            connection_limit: connection_limit.map(PollSemaphore::new),
+            permit: None,
+            keep_alive_timeout,
+        }
+    }
+}
+
+impl<I> Accept for ServerIncoming<I>
+    where
+        I: Accept + Unpin,
+{
+    type Conn = ServerConn<I::Conn>;
+    type Error = I::Error;
+
+    fn poll_accept(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        let this = self.get_mut();
+
+        // hold a permit before accepting, so that excess connections stay in the listen backlog
+        if let Some(connection_limit) = &mut this.connection_limit {
+            if this.permit.is_none() {
+                match ready!(connection_limit.poll_acquire(cx)) {
+                    Some(permit) => this.permit = Some(permit),
+                    None => return Poll::Ready(None),
+                }
+            }
+        }
+
+        match ready!(Pin::new(&mut this.incoming).poll_accept(cx)) {
+            Some(Ok(conn)) => Poll::Ready(Some(Ok(ServerConn::new(conn, this.permit.take(), this.keep_alive_timeout)))),
+            Some(Err(err)) => Poll::Ready(Some(Err(err))),
+            None => Poll::Ready(None),
+        }
+    }
+}
+
+/// Number of requests currently being served on a connection, a request being served until its response body is sent.
+#[derive(Default)]
+pub(crate) struct ConnActivity {
+    in_flight: AtomicUsize,
+    // requests started and ended, so that the connection notices it went idle again
+    changes: AtomicUsize,
+    waker: AtomicWaker,
+}
+
+impl ConnActivity {
+    pub(crate) fn start_request(self: &Arc<Self>) -> InFlightRequest {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        self.changes.fetch_add(1, Ordering::Relaxed);
+        InFlightRequest(self.clone())
+    }
+
+    fn is_idle(&self) -> bool {
+        self.in_flight.load(Ordering::Relaxed) == 0
+    }
+
+    fn changes(&self) -> usize {
+        self.changes.load(Ordering::Relaxed)
+    }
+}
+
+pub(crate) struct InFlightRequest(Arc<ConnActivity>);
+
+impl Drop for InFlightRequest {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+        self.0.changes.fetch_add(1, Ordering::Relaxed);
+        // the request may end on another task, e.g. an HTTP/2 stream, while the connection waits to read
+        self.0.waker.wake();
+    }
+}
+
+/// A response body keeping its request in flight until it is sent or dropped, so that a streamed response pausing
+/// for longer than the keep-alive timeout does not get its connection closed.
+pub(crate) struct InFlightBody {
+    body: Body,
+    _in_flight: InFlightRequest,
+}
+
+impl InFlightBody {
+    pub(crate) fn new(body: Body, in_flight: InFlightRequest) -> InFlightBody {
+        InFlightBody {
+            body,
+            _in_flight: in_flight,
+        }
+    }
+}
+
+impl HttpBody for InFlightBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_data(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Bytes, hyper::Error>>> {
+        Pin::new(&mut self.get_mut().body).poll_data(cx)
+    }
+
+    fn poll_trailers(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<Option<HeaderMap>, hyper::Error>> {
+        Pin::new(&mut self.get_mut().body).poll_trailers(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+pub(crate) struct ServerConn<C> {
+    conn: C,
+    activity: Arc<ConnActivity>,
+    keep_alive: Option<(Duration, Pin<Box<Sleep>>)>,
+    seen_changes: usize,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl<C> ServerConn<C> {
+    fn new(conn: C, permit: Option<OwnedSemaphorePermit>, keep_alive_timeout: Option<Duration>) -> ServerConn<C> {
+        ServerConn {
+            conn,
+            activity: Arc::new(ConnActivity::default()),
+            keep_alive: keep_alive_timeout.map(|timeout| (timeout, Box::pin(tokio::time::sleep(timeout)))),
+            seen_changes: 0,
+            _permit: permit,
+        }
+    }
+
+    // only requests count as activity, so that HTTP/2 pings do not keep an idle connection open
+    fn poll_keep_alive(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if let Some((timeout, sleep)) = &mut self.keep_alive {
+            self.activity.waker.register(cx.waker());
+
+            let changes = self.activity.changes();
+            let is_idle = self.activity.is_idle();
+            if changes != self.seen_changes || !is_idle {
+                self.seen_changes = changes;
+                sleep.as_mut().reset(Instant::now() + *timeout);
+            }
+
+            if is_idle && sleep.as_mut().poll(cx).is_ready() {
+                return Poll::Ready(Err(io::Error::new(io::ErrorKind::TimedOut, "keep-alive timeout")));
+            }
+        }
+
+        Poll::Pending
+    }
+}
+
+/// Gives the requests in flight on an accepted connection, for both plain and TLS transports.
+pub(crate) trait TrackedConn {
+    fn activity(&self) -> Arc<ConnActivity>;
+}
+
+impl<C> TrackedConn for ServerConn<C> {
+    fn activity(&self) -> Arc<ConnActivity> {
+        self.activity.clone()
+    }
+}
+
+impl<C: RemoteAddr> RemoteAddr for ServerConn<C> {
+    fn remote_addr(&self) -> SocketAddr {
+        self.conn.remote_addr()
+    }
+}
+
+impl<C> AsyncRead for ServerConn<C>
+    where
+        C: AsyncRead + Unpin,
+{
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        match Pin::new(&mut this.conn).poll_read(cx, buf) {
+            Poll::Ready(result) => Poll::Ready(result),
+            Poll::Pending => this.poll_keep_alive(cx),
+        }
+    }
+}
+
+impl<C> AsyncWrite for ServerConn<C>
+    where
+        C: AsyncWrite + Unpin,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().conn).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().conn).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().conn).poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(self: Pin<&mut Self>, cx: &mut Context<'_>, bufs: &[io::IoSlice<'_>]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().conn).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.conn.is_write_vectored()
+    }
+}
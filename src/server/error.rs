@@ -1,3 +1,9 @@
+use std::time::Duration;
+
+use http::{HeaderMap, Method, Response, StatusCode};
+use hyper::Body;
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
 use thiserror::Error;
 
 use crate::server::{HttpResponse, HttpResult};
@@ -16,18 +22,84 @@ pub enum ApiError {
     #[error("Bad Request Error: {0}")]
     BadRequest(#[from] anyhow::Error),
 
+    /// Not an error, kept for compatibility: handlers can answer `HttpResponse::no_content` instead.
     #[error("Not Content: {0}")]
     NoContent(String),
+
+    #[error("Payload Too Large Error: {0}")]
+    PayloadTooLarge(String),
+
+    #[error("Unsupported Media Type Error: {0}")]
+    UnsupportedMediaType(String),
+
+    #[error("Not Acceptable Error: {0}")]
+    NotAcceptable(String),
+
+    #[error("Method Not Allowed Error, allowed methods: {0:?}")]
+    MethodNotAllowed(Vec<Method>),
+
+    #[error("Gateway Timeout Error: {0}")]
+    GatewayTimeout(String),
+
+    /// `401 Unauthorized`, with `challenge` as the `WWW-Authenticate` header, e.g. `Bearer realm="api"`.
+    #[error("Unauthorized Error: {message}")]
+    Unauthorized { challenge: String, message: String },
+
+    #[error("Conflict Error: {0}")]
+    Conflict(String),
+
+    #[error("Precondition Failed Error: {0}")]
+    PreconditionFailed(String),
+
+    #[error("Unprocessable Entity Error: {0}")]
+    UnprocessableEntity(String),
+
+    /// `429 Too Many Requests`, with `retry_after` as the `Retry-After` header when set.
+    #[error("Too Many Requests Error: {message}")]
+    TooManyRequests { retry_after: Option<Duration>, message: String },
+
+    /// `503 Service Unavailable`, with `retry_after` as the `Retry-After` header when set.
+    #[error("Service Unavailable Error: {message}")]
+    ServiceUnavailable { retry_after: Option<Duration>, message: String },
+
+    /// Any other status, with `headers` added to the response.
+    #[error("{code} Error: {message}")]
+    Status { code: StatusCode, message: String, headers: HeaderMap },
+}
+
+impl ApiError {
+    /// The response answering this error, a bare `500 Internal Server Error` if it cannot be built.
+    pub(crate) fn into_response(self) -> Response<Body> {
+        HttpResult::from(self).unwrap_or_else(|err| {
+            error!("Error in building error response: {}", err);
+
+            let mut response = Response::new(Body::empty());
+            *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+            response
+        })
+    }
 }
 
-impl Into<HttpResult> for ApiError {
-    fn into(self) -> HttpResult {
-        match self {
+impl From<ApiError> for HttpResult {
+    fn from(error: ApiError) -> HttpResult {
+        match error {
             ApiError::InternalServerError(error) => HttpResponse::internal_server_error(error),
             ApiError::NotFound(reason) => HttpResponse::not_found(&reason),
             ApiError::Forbidden(reason) => HttpResponse::forbidden(&reason),
             ApiError::BadRequest(error) => HttpResponse::bad_request(error),
             ApiError::NoContent(reason) => HttpResponse::no_content(&reason),
+            ApiError::PayloadTooLarge(reason) => HttpResponse::payload_too_large(&reason),
+            ApiError::UnsupportedMediaType(reason) => HttpResponse::unsupported_media_type(&reason),
+            ApiError::NotAcceptable(reason) => HttpResponse::not_acceptable(&reason),
+            ApiError::MethodNotAllowed(allow) => HttpResponse::method_not_allowed(&allow),
+            ApiError::GatewayTimeout(reason) => HttpResponse::gateway_timeout(&reason),
+            ApiError::Unauthorized { challenge, message } => HttpResponse::unauthorized(&challenge, &message),
+            ApiError::Conflict(reason) => HttpResponse::conflict(&reason),
+            ApiError::PreconditionFailed(reason) => HttpResponse::precondition_failed(&reason),
+            ApiError::UnprocessableEntity(reason) => HttpResponse::unprocessable_entity(&reason),
+            ApiError::TooManyRequests { retry_after, message } => HttpResponse::too_many_requests(retry_after, &message),
+            ApiError::ServiceUnavailable { retry_after, message } => HttpResponse::service_unavailable(retry_after, &message),
+            ApiError::Status { code, message, headers } => HttpResponse::status(code, &message, headers),
         }
     }
 }
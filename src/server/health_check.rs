@@ -1,6 +1,6 @@
 use std::sync::atomic::Ordering;
 
-use http::Method;
+use http::{Method, StatusCode};
 use hyper::Body;
 
 use crate::server::HttpResult;
@@ -24,16 +24,16 @@ pub fn get_in_rotation_status(route: &HttpRoute<'_>) -> HttpResult {
     } else {
         const NOK: &str = "NOK";
 
-        HttpResponse::internal_server_error(anyhow::anyhow!(NOK))
+        HttpResponse::problem(StatusCode::INTERNAL_SERVER_ERROR, Some(NOK))
     }
 }
 
 pub fn oor_handler(route: &HttpRoute<'_>) -> HttpResult {
     let method = route.method;
 
-    match method {
-        &Method::GET => switch_oor_status(route),
-        &Method::POST => switch_oor_status(route),
+    match *method {
+        Method::GET => switch_oor_status(route),
+        Method::POST => switch_oor_status(route),
         _ => HttpResponse::not_found(route.path),
     }
 }
@@ -1,62 +1,272 @@
+use std::fmt;
+use std::io::{Error as IOError, ErrorKind as IOErrorKind};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
 use anyhow::Context;
-use bytes::Buf;
-use futures::{Stream, TryStreamExt};
+use bytes::{Buf, Bytes, BytesMut};
+use futures::{future, Stream, TryStreamExt};
+use http::header;
 use hyper::Body;
 use serde::Deserialize;
 
+use super::codec::{accepted_content_types, Format};
+#[cfg(feature = "protobuf")]
+use super::codec::{is_protobuf, PROTOBUF_CONTENT_TYPE};
 use super::commons::{BR_CONTENT_ENCODING, DEFLATE_CONTENT_ENCODING, GZIP_CONTENT_ENCODING};
-use super::HttpRoute;
+#[cfg(feature = "zstd")]
+use super::commons::ZSTD_CONTENT_ENCODING;
+#[cfg(feature = "metrics")]
+use super::logger::METRICS_LOGGER;
+#[cfg(feature = "websocket")]
+use super::websocket::{self, WebSocket};
+#[cfg(feature = "websocket")]
+use super::HttpResult;
+use super::{ApiError, ApiResult, HttpRoute};
+
+// small bodies are let through whatever their ratio, as the first decoded chunk alone can be far larger than its input
+const MIN_DECOMPRESSED_SIZE_FOR_RATIO: usize = 64 * 1024;
 
 pub struct HttpRequest;
 
 impl HttpRequest {
-    pub async fn bytes(route: &HttpRoute<'_>, body: Body) -> anyhow::Result<impl Buf> {
-        // TODO: validate content length
-        // let content_length = route.req.headers().get(header::CONTENT_LENGTH);
-
-        use std::io::{Error as IOError, ErrorKind as IOErrorKind};
-
-        let body = if let Some(content_encoding) = &route.content_encoding {
-            match &content_encoding[..] {
-                BR_CONTENT_ENCODING => Body::wrap_stream(brotli_decode(
-                    body.map_err(|_| IOError::from(IOErrorKind::InvalidData)),
-                )),
-                DEFLATE_CONTENT_ENCODING => Body::wrap_stream(deflate_decode(
-                    body.map_err(|_| IOError::from(IOErrorKind::InvalidData)),
-                )),
-                GZIP_CONTENT_ENCODING => Body::wrap_stream(gzip_decode(
-                    body.map_err(|_| IOError::from(IOErrorKind::InvalidData)),
-                )),
-                _ => {
-                    // do nothing
-                    body
-                }
+    /// Reads the whole body, limited to `max_body_size` from the server config.
+    pub async fn bytes(route: &HttpRoute<'_>, body: Body) -> ApiResult<impl Buf> {
+        Self::bytes_with_limit(route, body, route.config.max_body_size).await
+    }
+
+    /// Reads the whole body, limited to `max_body_size` bytes as received on the wire.
+    pub async fn bytes_with_limit(route: &HttpRoute<'_>, body: Body, max_body_size: usize) -> ApiResult<impl Buf> {
+        let content_length = content_length(route)?;
+        if let Some(content_length) = content_length {
+            if content_length > max_body_size {
+                return Err(ApiError::PayloadTooLarge(format!("Content-Length: {} exceeds limit of {} bytes", content_length, max_body_size)));
             }
-        } else {
-            body
-        };
+        }
+
+        let received = Arc::new(AtomicUsize::new(0));
+        let received_on_wire = received.clone();
+        let body = body
+            .map_err(|err| IOError::new(IOErrorKind::InvalidData, err))
+            .and_then(move |chunk| {
+                let received = received_on_wire.fetch_add(chunk.len(), Ordering::Relaxed) + chunk.len();
+                if received > max_body_size {
+                    future::ready(Err(IOError::new(IOErrorKind::InvalidData, BodyTooLarge(max_body_size))))
+                } else {
+                    future::ready(Ok(chunk))
+                }
+            });
+
+        let body = decode(route, Box::pin(body), received)?;
 
         // Aggregate the body...
-        hyper::body::aggregate(body)
+        let capacity = content_length.unwrap_or_default();
+        let whole_body = body
+            .try_fold(BytesMut::with_capacity(capacity), |mut whole_body, chunk| {
+                whole_body.extend_from_slice(&chunk);
+                future::ready(Ok(whole_body))
+            })
             .await
-            .with_context(|| "Error in aggregating body")
+            .map_err(body_error)?;
+
+        Ok(whole_body.freeze())
+    }
+
+    /// Reads the whole body and de-serialises it according to its `Content-Type`, JSON when the header is absent.
+    pub async fn value<T>(route: &HttpRoute<'_>, body: Body) -> ApiResult<T>
+        where
+            T: for<'de> Deserialize<'de>,
+    {
+        Self::value_with_limit(route, body, route.config.max_body_size).await
     }
 
-    pub async fn value<T>(route: &HttpRoute<'_>, body: Body) -> anyhow::Result<T>
+    pub async fn value_with_limit<T>(route: &HttpRoute<'_>, body: Body, max_body_size: usize) -> ApiResult<T>
         where
             T: for<'de> Deserialize<'de>,
     {
-        // TODO: de-serialise based on content type
-        // let content_type = route.req.headers().get(header::CONTENT_TYPE);
+        let format = content_format(route)?;
+
+        let whole_body = Self::bytes_with_limit(route, body, max_body_size).await?;
+
+        let value = format.decode(whole_body)?;
+
+        Ok(value)
+    }
+
+    /// Accepts a WebSocket upgrade request, returning the `101 Switching Protocols` response to send back. Once the
+    /// connection is switched, `handler` is called on a task of its own with the socket.
+    #[cfg(feature = "websocket")]
+    pub fn upgrade_websocket<F, Fut>(route: &HttpRoute<'_>, handler: F) -> HttpResult
+        where
+            F: FnOnce(WebSocket) -> Fut + Send + 'static,
+            Fut: std::future::Future<Output=()> + Send + 'static,
+    {
+        websocket::upgrade(route, handler)
+    }
+
+    /// Reads the whole body as a protobuf message, sent as `application/x-protobuf` or without a `Content-Type`.
+    #[cfg(feature = "protobuf")]
+    pub async fn proto<M>(route: &HttpRoute<'_>, body: Body) -> ApiResult<M>
+        where
+            M: prost::Message + Default,
+    {
+        if let Some(content_type) = route.req.headers().get(header::CONTENT_TYPE) {
+            if !content_type.to_str().is_ok_and(is_protobuf) {
+                return Err(ApiError::UnsupportedMediaType(format!(
+                    "Content-Type: {} is not supported, expected: {}",
+                    String::from_utf8_lossy(content_type.as_bytes()),
+                    PROTOBUF_CONTENT_TYPE
+                )));
+            }
+        }
 
         let whole_body = Self::bytes(route, body).await?;
 
-        // Decode as JSON...
-        serde_json::from_reader(whole_body.reader())
-            .with_context(|| "Error in decoding body_as_value")
+        let message = M::decode(whole_body).with_context(|| "Error in decoding protobuf body")?;
+
+        Ok(message)
+    }
+
+    /// Same as `proto`, but also reads the JSON transcoding of the message when sent as `application/json`, which comes
+    /// in handy for debugging with curl. The JSON is de-serialised with serde into the Rust struct, not read as the
+    /// canonical protobuf JSON mapping.
+    #[cfg(feature = "protobuf")]
+    pub async fn proto_or_json<M>(route: &HttpRoute<'_>, body: Body) -> ApiResult<M>
+        where
+            M: prost::Message + Default + for<'de> Deserialize<'de>,
+    {
+        let content_type = route.req.headers().get(header::CONTENT_TYPE).and_then(|value| value.to_str().ok());
+        if content_type.and_then(Format::from_content_type) == Some(Format::Json) {
+            return Self::value(route, body).await;
+        }
+
+        Self::proto(route, body).await
+    }
+}
+
+type BodyStream<'a> = Pin<Box<dyn Stream<Item=std::io::Result<Bytes>> + Send + 'a>>;
+
+/// Undoes the request codings, last applied first, each decoded stage being bounded by `limit_decoded`.
+fn decode<'a>(route: &HttpRoute<'_>, mut body: BodyStream<'a>, received: Arc<AtomicUsize>) -> ApiResult<BodyStream<'a>> {
+    for coding in route.content_codings.iter().rev() {
+        body = match &coding[..] {
+            BR_CONTENT_ENCODING => Box::pin(limit_decoded(route, brotli_decode(body), received.clone())),
+            DEFLATE_CONTENT_ENCODING => Box::pin(limit_decoded(route, deflate_decode(body), received.clone())),
+            GZIP_CONTENT_ENCODING => Box::pin(limit_decoded(route, gzip_decode(body), received.clone())),
+            #[cfg(feature = "zstd")]
+            ZSTD_CONTENT_ENCODING => Box::pin(limit_decoded(route, zstd_decode(body), received.clone())),
+            _ => {
+                return Err(ApiError::UnsupportedMediaType(format!(
+                    "Content-Encoding: {} is not supported",
+                    String::from_utf8_lossy(coding)
+                )));
+            }
+        };
+    }
+
+    Ok(body)
+}
+
+fn content_format(route: &HttpRoute<'_>) -> ApiResult<Format> {
+    let content_type = match route.req.headers().get(header::CONTENT_TYPE) {
+        Some(content_type) => content_type,
+        None => return Ok(Format::Json),
+    };
+
+    content_type
+        .to_str()
+        .ok()
+        .and_then(Format::from_content_type)
+        .ok_or_else(|| {
+            ApiError::UnsupportedMediaType(format!(
+                "Content-Type: {} is not supported, expected one of: {}",
+                String::from_utf8_lossy(content_type.as_bytes()),
+                accepted_content_types()
+            ))
+        })
+}
+
+fn content_length(route: &HttpRoute<'_>) -> ApiResult<Option<usize>> {
+    match route.req.headers().get(header::CONTENT_LENGTH) {
+        Some(content_length) => {
+            let content_length = content_length
+                .to_str()
+                .ok()
+                .and_then(|content_length| content_length.parse::<usize>().ok())
+                .with_context(|| format!("Invalid Content-Length: {:?}", content_length))?;
+
+            Ok(Some(content_length))
+        }
+        None => Ok(None),
     }
 }
 
+#[derive(Debug)]
+struct BodyTooLarge(usize);
+
+impl fmt::Display for BodyTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Body exceeds limit of {} bytes", self.0)
+    }
+}
+
+impl std::error::Error for BodyTooLarge {}
+
+#[derive(Debug)]
+struct DecompressionLimitExceeded(String);
+
+impl fmt::Display for DecompressionLimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Decompressed body {}", self.0)
+    }
+}
+
+impl std::error::Error for DecompressionLimitExceeded {}
+
+fn body_error(error: IOError) -> ApiError {
+    if let Some(inner) = error.get_ref() {
+        if let Some(body_too_large) = inner.downcast_ref::<BodyTooLarge>() {
+            return ApiError::PayloadTooLarge(body_too_large.to_string());
+        }
+
+        if let Some(decompression_limit_exceeded) = inner.downcast_ref::<DecompressionLimitExceeded>() {
+            #[cfg(feature = "metrics")]
+            METRICS_LOGGER.log_decompression_limit_exceeded();
+
+            return ApiError::PayloadTooLarge(decompression_limit_exceeded.to_string());
+        }
+    }
+
+    ApiError::BadRequest(anyhow::Error::new(error).context("Error in aggregating body"))
+}
+
+/// Guards against decompression bombs, by bounding both the decoded size and its ratio to the bytes `received` on the wire.
+fn limit_decoded(
+    route: &HttpRoute<'_>,
+    decoded: impl Stream<Item=std::io::Result<Bytes>>,
+    received: Arc<AtomicUsize>,
+) -> impl Stream<Item=std::io::Result<Bytes>> {
+    let max_decompressed_size = route.config.max_decompressed_size;
+    let max_decompression_ratio = route.config.max_decompression_ratio;
+
+    let mut decoded_size = 0usize;
+    decoded.and_then(move |chunk| {
+        decoded_size += chunk.len();
+        let received = received.load(Ordering::Relaxed);
+
+        let result = if decoded_size > max_decompressed_size {
+            Err(DecompressionLimitExceeded(format!("exceeds limit of {} bytes", max_decompressed_size)))
+        } else if decoded_size > MIN_DECOMPRESSED_SIZE_FOR_RATIO && decoded_size > received.saturating_mul(max_decompression_ratio) {
+            Err(DecompressionLimitExceeded(format!("exceeds {} times the {} bytes received", max_decompression_ratio, received)))
+        } else {
+            Ok(chunk)
+        };
+
+        future::ready(result.map_err(|err| IOError::new(IOErrorKind::InvalidData, err)))
+    })
+}
+
 fn gzip_decode(
     input: impl Stream<Item=std::io::Result<bytes::Bytes>>,
 ) -> impl Stream<Item=std::io::Result<bytes::Bytes>> {
@@ -80,3 +290,78 @@ fn deflate_decode(
         tokio_util::io::StreamReader::new(input),
     ))
 }
+
+#[cfg(feature = "zstd")]
+fn zstd_decode(
+    input: impl Stream<Item=std::io::Result<bytes::Bytes>>,
+) -> impl Stream<Item=std::io::Result<bytes::Bytes>> {
+    tokio_util::io::ReaderStream::new(async_compression::tokio::bufread::ZstdDecoder::new(
+        tokio_util::io::StreamReader::new(input),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use futures::stream;
+    use http::Request;
+
+    use super::*;
+    use crate::server::ServerConfig;
+
+    async fn decoded_size(config: &ServerConfig, received: usize, chunks: &[usize]) -> ApiResult<usize> {
+        let req = Request::post("/api/test").body(Body::empty()).unwrap();
+        let route = HttpRoute::with_config(&req, chrono::Local::now(), Instant::now(), ([127, 0, 0, 1], 0).into(), config);
+
+        let chunks = chunks.iter().map(|size| Ok(Bytes::from(vec![0; *size]))).collect::<Vec<_>>();
+        limit_decoded(&route, stream::iter(chunks), Arc::new(AtomicUsize::new(received)))
+            .try_fold(0, |size, chunk| future::ready(Ok(size + chunk.len())))
+            .await
+            .map_err(body_error)
+    }
+
+    fn config(max_decompressed_size: usize, max_decompression_ratio: usize) -> ServerConfig {
+        ServerConfig {
+            max_decompressed_size,
+            max_decompression_ratio,
+            ..ServerConfig::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn limits_decoded_size() {
+        let config = config(100 * 1024, 1000);
+
+        assert_eq!(decoded_size(&config, 1024, &[60 * 1024, 40 * 1024]).await.unwrap(), 100 * 1024);
+        assert!(matches!(
+            decoded_size(&config, 1024, &[60 * 1024, 40 * 1024, 1]).await,
+            Err(ApiError::PayloadTooLarge(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn limits_decompression_ratio() {
+        let config = config(usize::MAX, 10);
+
+        assert_eq!(decoded_size(&config, 10 * 1024, &[64 * 1024, 36 * 1024]).await.unwrap(), 100 * 1024);
+        assert!(matches!(
+            decoded_size(&config, 10 * 1024, &[64 * 1024, 36 * 1024 + 1]).await,
+            Err(ApiError::PayloadTooLarge(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn lets_small_bodies_through_whatever_their_ratio() {
+        let config = config(usize::MAX, 10);
+
+        assert_eq!(
+            decoded_size(&config, 1, &[MIN_DECOMPRESSED_SIZE_FOR_RATIO]).await.unwrap(),
+            MIN_DECOMPRESSED_SIZE_FOR_RATIO
+        );
+        assert!(matches!(
+            decoded_size(&config, 1, &[MIN_DECOMPRESSED_SIZE_FOR_RATIO, 1]).await,
+            Err(ApiError::PayloadTooLarge(_))
+        ));
+    }
+}
@@ -1,46 +1,97 @@
+use std::time::Duration;
+
 use anyhow::Context;
-use futures::{Stream, TryStreamExt};
-use http::{header, Response};
-use http::{HeaderValue, StatusCode};
+use bytes::Bytes;
+use futures::{future, stream, Stream, StreamExt, TryStreamExt};
+use http::{header, Method, Response};
+use http::{HeaderMap, HeaderValue, StatusCode};
 use hyper::Body;
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
 use serde::Serialize;
 
-use crate::server::{HttpResult, HttpRoute};
+use crate::server::{ApiError, HttpResult, HttpRoute};
 use crate::server::commons::get_hostname_header;
 
+use super::codec::{available_content_types, Format};
+#[cfg(feature = "protobuf")]
+use super::codec::{negotiate_protobuf, ProtobufFormat, PROTOBUF_CONTENT_TYPE};
+use super::request_context::{current_request_id, REQUEST_CONTEXT};
+use super::sse::{event_stream, SseEvent};
 use super::commons::{BR_CONTENT_ENCODING, DEFLATE_CONTENT_ENCODING, GZIP_CONTENT_ENCODING};
+#[cfg(feature = "zstd")]
+use super::commons::ZSTD_CONTENT_ENCODING;
 
 pub struct HttpResponse;
 
+const PROBLEM_CONTENT_TYPE: &str = "application/problem+json";
+
+/// RFC 9457 problem details, the body of error responses.
+#[derive(Serialize)]
+struct Problem<'a> {
+    #[serde(rename = "type")]
+    problem_type: &'static str,
+    title: &'static str,
+    status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    instance: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<&'a str>,
+}
+
+/// Response extension that keeps `HttpResponse::compress_response` from encoding the body.
+#[derive(Clone, Copy, Debug)]
+pub struct NoCompression;
+
 lazy_static! {
     static ref BR_HEADER_VALUE: HeaderValue = HeaderValue::from_static("br");
     static ref DEFLATE_HEADER_VALUE: HeaderValue = HeaderValue::from_static("deflate");
     static ref GZIP_HEADER_VALUE: HeaderValue = HeaderValue::from_static("gzip");
+    static ref VARY_ACCEPT_HEADER_VALUE: HeaderValue = HeaderValue::from_static("Accept");
+    static ref VARY_ACCEPT_ENCODING_HEADER_VALUE: HeaderValue = HeaderValue::from_static("Accept-Encoding");
+}
+
+#[cfg(feature = "zstd")]
+lazy_static! {
+    static ref ZSTD_HEADER_VALUE: HeaderValue = HeaderValue::from_static("zstd");
+}
+
+// read from the request's server config, errors built outside of request handling showing no details
+fn expose_error_details() -> bool {
+    REQUEST_CONTEXT
+        .try_with(|context| context.expose_error_details)
+        .unwrap_or(false)
 }
 
 impl HttpResponse {
+    /// Logs `error`, which is only shown to the client when `expose_error_details` is on.
     pub fn internal_server_error(error: anyhow::Error) -> HttpResult {
-        let body = Body::from(format!("Error in serving request ==> {:?}", error));
+        let request_id = current_request_id().unwrap_or_default();
+        error!("Error in serving request {}: {:?}", request_id, error);
+
+        let detail = expose_error_details().then(|| format!("{:#}", error));
 
-        HttpResponse::build_response(StatusCode::INTERNAL_SERVER_ERROR, body)
+        HttpResponse::problem(StatusCode::INTERNAL_SERVER_ERROR, detail.as_deref())
     }
 
     pub fn not_found(reason: &str) -> HttpResult {
-        let body = Body::from(format!("Not found: {}", reason));
-
-        HttpResponse::build_response(StatusCode::NOT_FOUND, body)
+        HttpResponse::problem(StatusCode::NOT_FOUND, Some(reason))
     }
 
     pub fn forbidden(reason: &str) -> HttpResult {
-        let body = Body::from(format!("Forbidden: {}", reason));
-
-        HttpResponse::build_response(StatusCode::FORBIDDEN, body)
+        HttpResponse::problem(StatusCode::FORBIDDEN, Some(reason))
     }
 
+    /// Shows the client the outermost message of `error`, and its whole chain when `expose_error_details` is on.
     pub fn bad_request(error: anyhow::Error) -> HttpResult {
-        let body = Body::from(format!("Bad Request: {:?}", error));
+        let detail = match expose_error_details() {
+            true => format!("{:#}", error),
+            false => error.to_string(),
+        };
 
-        HttpResponse::build_response(StatusCode::BAD_REQUEST, body)
+        HttpResponse::problem(StatusCode::BAD_REQUEST, Some(&detail))
     }
 
     pub fn no_content(reason: &str) -> HttpResult {
@@ -49,6 +100,119 @@ impl HttpResponse {
         HttpResponse::build_response(StatusCode::NO_CONTENT, body)
     }
 
+    pub fn payload_too_large(reason: &str) -> HttpResult {
+        HttpResponse::problem(StatusCode::PAYLOAD_TOO_LARGE, Some(reason))
+    }
+
+    pub fn unsupported_media_type(reason: &str) -> HttpResult {
+        HttpResponse::problem(StatusCode::UNSUPPORTED_MEDIA_TYPE, Some(reason))
+    }
+
+    pub fn not_acceptable(reason: &str) -> HttpResult {
+        HttpResponse::problem(StatusCode::NOT_ACCEPTABLE, Some(reason))
+    }
+
+    pub fn gateway_timeout(reason: &str) -> HttpResult {
+        HttpResponse::problem(StatusCode::GATEWAY_TIMEOUT, Some(reason))
+    }
+
+    pub fn method_not_allowed(allow: &[Method]) -> HttpResult {
+        let allow = allow_header(allow);
+        let detail = format!("Allowed methods are {}", allow);
+
+        let allow = HeaderValue::from_str(&allow).with_context(|| "Error in building Allow header")?;
+
+        let mut response = HttpResponse::problem(StatusCode::METHOD_NOT_ALLOWED, Some(&detail))?;
+        response.headers_mut().insert(header::ALLOW, allow);
+
+        Ok(response)
+    }
+
+    /// `401 Unauthorized`, with `challenge` as the `WWW-Authenticate` header, e.g. `Bearer realm="api"`.
+    pub fn unauthorized(challenge: &str, reason: &str) -> HttpResult {
+        let challenge = HeaderValue::from_str(challenge).with_context(|| "Error in building WWW-Authenticate header")?;
+
+        let mut response = HttpResponse::problem(StatusCode::UNAUTHORIZED, Some(reason))?;
+        response.headers_mut().insert(header::WWW_AUTHENTICATE, challenge);
+
+        Ok(response)
+    }
+
+    pub fn conflict(reason: &str) -> HttpResult {
+        HttpResponse::problem(StatusCode::CONFLICT, Some(reason))
+    }
+
+    pub fn precondition_failed(reason: &str) -> HttpResult {
+        HttpResponse::problem(StatusCode::PRECONDITION_FAILED, Some(reason))
+    }
+
+    pub fn unprocessable_entity(reason: &str) -> HttpResult {
+        HttpResponse::problem(StatusCode::UNPROCESSABLE_ENTITY, Some(reason))
+    }
+
+    /// `429 Too Many Requests`, telling the client to wait `retry_after` when set.
+    pub fn too_many_requests(retry_after: Option<Duration>, reason: &str) -> HttpResult {
+        let mut response = HttpResponse::problem(StatusCode::TOO_MANY_REQUESTS, Some(reason))?;
+        insert_retry_after(&mut response, retry_after);
+
+        Ok(response)
+    }
+
+    /// `503 Service Unavailable`, telling the client to wait `retry_after` when set.
+    pub fn service_unavailable(retry_after: Option<Duration>, reason: &str) -> HttpResult {
+        let mut response = HttpResponse::problem(StatusCode::SERVICE_UNAVAILABLE, Some(reason))?;
+        insert_retry_after(&mut response, retry_after);
+
+        Ok(response)
+    }
+
+    /// Error response for any `code`, with `headers` added to it.
+    pub fn status(code: StatusCode, reason: &str, headers: HeaderMap) -> HttpResult {
+        let mut response = HttpResponse::problem(code, Some(reason))?;
+        response.headers_mut().extend(headers);
+
+        Ok(response)
+    }
+
+    /// Error response as `application/problem+json` (RFC 9457), e.g.
+    /// `{"type":"about:blank","title":"Not Found","status":404,"detail":"..","instance":"/api/users/7","request_id":".."}`.
+    /// The request path is the `instance`, and `request_id` is `HttpRoute::request_id`.
+    pub fn problem(code: StatusCode, detail: Option<&str>) -> HttpResult {
+        let problem = |instance: Option<&str>, request_id: Option<&str>| {
+            let problem = Problem {
+                problem_type: "about:blank",
+                title: code.canonical_reason().unwrap_or_default(),
+                status: code.as_u16(),
+                detail,
+                instance,
+                request_id,
+            };
+            serde_json::to_vec(&problem).with_context(|| "Error in serialising problem details")
+        };
+        let body = REQUEST_CONTEXT
+            .try_with(|context| problem(Some(&context.path), Some(&context.request_id)))
+            .unwrap_or_else(|_| problem(None, None))?;
+
+        let response = Response::builder()
+            .status(code)
+            .header(header::HOST, get_hostname_header().clone())
+            .header(header::CONTENT_TYPE, PROBLEM_CONTENT_TYPE)
+            .body(Body::from(body))
+            .with_context(|| "Error in building HttpResponse")?;
+
+        Ok(response)
+    }
+
+    /// Answers an `OPTIONS` request with the methods allowed on its path.
+    pub fn options(allow: &[Method]) -> HttpResult {
+        let allow = HeaderValue::from_str(&allow_header(allow)).with_context(|| "Error in building Allow header")?;
+
+        let mut response = HttpResponse::build_response(StatusCode::NO_CONTENT, Body::empty())?;
+        response.headers_mut().insert(header::ALLOW, allow);
+
+        Ok(response)
+    }
+
     fn build_response(code: StatusCode, body: Body) -> HttpResult {
         let response = Response::builder()
             .status(code)
@@ -106,13 +270,14 @@ impl HttpResponse {
         Ok(Self::compress_response(route, response))
     }
 
+    /// Sends an encoded protobuf message as `application/x-protobuf`, `proto/bytes` before 0.4.
     pub fn proto_binary(route: &HttpRoute<'_>, body: Vec<u8>) -> HttpResult
     {
         let body = Body::from(body);
 
         let response = Response::builder()
             .status(StatusCode::OK)
-            .header(header::CONTENT_TYPE, "proto/bytes")
+            .header(header::CONTENT_TYPE, "application/x-protobuf")
             .header(header::HOST, get_hostname_header().clone())
             .body(body)
             .with_context(|| "Error in building HttpResponse")?;
@@ -120,17 +285,60 @@ impl HttpResponse {
         Ok(Self::compress_response(route, response))
     }
 
-    // TODO: serialise response object to accept format
+    /// Encodes a protobuf message as `application/x-protobuf`.
+    #[cfg(feature = "protobuf")]
+    pub fn proto<M>(route: &HttpRoute<'_>, message: &M) -> HttpResult
+        where
+            M: prost::Message,
+    {
+        Self::proto_binary(route, message.encode_to_vec())
+    }
+
+    /// Encodes a protobuf message, or its JSON transcoding when the request `Accept` header prefers JSON. The JSON is
+    /// the serde serialisation of the Rust struct, not the canonical protobuf JSON mapping: field names stay snake_case,
+    /// enums are numbers and 64-bit integers are not quoted.
+    #[cfg(feature = "protobuf")]
+    pub fn proto_or_json<M>(route: &HttpRoute<'_>, message: &M) -> HttpResult
+        where
+            M: prost::Message + Serialize,
+    {
+        let accept = route.req.headers().get(header::ACCEPT).and_then(|value| value.to_str().ok());
+        let response = match negotiate_protobuf(accept) {
+            Some(ProtobufFormat::Protobuf) => Self::proto(route, message),
+            Some(ProtobufFormat::Json) => Self::json(route, message),
+            None => {
+                return Err(ApiError::NotAcceptable(format!(
+                    "Accept: {} is not satisfiable, available types: {}, application/json",
+                    accept.unwrap_or_default(),
+                    PROTOBUF_CONTENT_TYPE
+                )));
+            }
+        };
+
+        response.map(|mut response| {
+            response.headers_mut().append(header::VARY, VARY_ACCEPT_HEADER_VALUE.clone());
+            response
+        })
+    }
+
+    /// Serialises `body` to the format preferred by the request `Accept` header, among JSON and the enabled `msgpack`,
+    /// `cbor` and `bincode` features.
     pub fn binary_or_json<S>(route: &HttpRoute<'_>, body: &S) -> HttpResult
         where
             S: Serialize,
     {
-        let body = serde_json::to_vec(body).with_context(|| "Error in serialising")?;
+        let accept = route.req.headers().get(header::ACCEPT).and_then(|value| value.to_str().ok());
+        let format = Format::negotiate(accept).ok_or_else(|| {
+            ApiError::NotAcceptable(format!("Accept: {} is not satisfiable, available types: {}", accept.unwrap_or_default(), available_content_types()))
+        })?;
+
+        let body = format.encode(body).map_err(ApiError::InternalServerError)?;
         let body = Body::from(body);
 
         let response = Response::builder()
             .status(StatusCode::OK)
-            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::CONTENT_TYPE, format.media_types()[0])
+            .header(header::VARY, VARY_ACCEPT_HEADER_VALUE.clone())
             .header(header::HOST, get_hostname_header().clone())
             .body(body)
             .with_context(|| "Error in building HttpResponse")?;
@@ -155,6 +363,84 @@ impl HttpResponse {
         Ok(Self::compress_response(route, response))
     }
 
+    /// Streams `body` as a chunked response. Chunks are pulled from the stream only as fast as the client reads them.
+    pub fn stream<S, E>(route: &HttpRoute<'_>, content_type: &str, body: S) -> HttpResult
+        where
+            S: Stream<Item=Result<Bytes, E>> + Send + 'static,
+            E: Into<Box<dyn std::error::Error + Send + Sync>> + 'static,
+    {
+        let response = Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, content_type)
+            .header(header::HOST, get_hostname_header().clone())
+            .body(Body::wrap_stream(body))
+            .with_context(|| "Error in building HttpResponse")?;
+
+        Ok(Self::compress_response(route, response))
+    }
+
+    /// Streams `items` as a JSON array, serialising each item as it is pulled from the stream. An error ends the body
+    /// abruptly, so that the client never mistakes a partial array for a complete one.
+    pub fn json_array_stream<S, T, E>(route: &HttpRoute<'_>, items: S) -> HttpResult
+        where
+            S: Stream<Item=Result<T, E>> + Send + 'static,
+            T: Serialize,
+            E: Into<Box<dyn std::error::Error + Send + Sync>> + 'static,
+    {
+        let mut separator: &'static [u8] = b"";
+        let items = items.map(move |item| {
+            let mut chunk = separator.to_vec();
+            separator = b",";
+
+            serialise_json_chunk(item, &mut chunk)?;
+            Ok::<_, Box<dyn std::error::Error + Send + Sync>>(Bytes::from(chunk))
+        });
+
+        let body = stream::once(future::ready(Ok(Bytes::from_static(b"["))))
+            .chain(items)
+            .chain(stream::once(future::ready(Ok(Bytes::from_static(b"]")))));
+
+        Self::stream(route, "application/json", body)
+    }
+
+    /// Streams `items` as newline delimited JSON, one item per line.
+    pub fn ndjson_stream<S, T, E>(route: &HttpRoute<'_>, items: S) -> HttpResult
+        where
+            S: Stream<Item=Result<T, E>> + Send + 'static,
+            T: Serialize,
+            E: Into<Box<dyn std::error::Error + Send + Sync>> + 'static,
+    {
+        let body = items.map(|item| {
+            let mut chunk = Vec::new();
+            serialise_json_chunk(item, &mut chunk)?;
+            chunk.push(b'\n');
+            Ok::<_, Box<dyn std::error::Error + Send + Sync>>(Bytes::from(chunk))
+        });
+
+        Self::stream(route, "application/x-ndjson", body)
+    }
+
+    /// Streams `events` as Server-Sent Events, with keep-alive comments every `sse_keep_alive_interval`. The response is
+    /// never compressed, as encoders would hold events back until their buffer fills.
+    pub fn sse<S, E>(route: &HttpRoute<'_>, events: S) -> HttpResult
+        where
+            S: Stream<Item=Result<SseEvent, E>> + Send + 'static,
+            E: Into<Box<dyn std::error::Error + Send + Sync>> + 'static,
+    {
+        let body = event_stream(events, route.config.sse_keep_alive_interval);
+
+        let response = Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, "text/event-stream")
+            .header(header::CACHE_CONTROL, "no-cache")
+            .header(header::HOST, get_hostname_header().clone())
+            .extension(NoCompression)
+            .body(Body::wrap_stream(body))
+            .with_context(|| "Error in building HttpResponse")?;
+
+        Ok(response)
+    }
+
     // pub fn response_visitor<S, F>(
     //     route: &HttpRoute<'_>,
     //     visitor: F,
@@ -208,42 +494,91 @@ impl HttpResponse {
     //     Ok(Self::compress_response(route, response))
     // }
 
+    /// Same as `ok`, but never compressed, e.g. for payloads that are already compressed.
+    pub fn ok_uncompressed(body: Body) -> HttpResult {
+        let response = Response::builder()
+            .status(StatusCode::OK)
+            .header(header::HOST, get_hostname_header().clone())
+            .extension(NoCompression)
+            .body(body)
+            .with_context(|| "Error in building HttpResponse")?;
+
+        Ok(response)
+    }
+
     pub fn compress_response(
         route: &HttpRoute<'_>,
         mut response: Response<Body>,
     ) -> Response<Body> {
         use std::io::{Error as IOError, ErrorKind as IOErrorKind};
 
+        if !Self::is_compressible(route, &response) {
+            return response;
+        }
+
+        // the representation depends on Accept-Encoding, so caches must key on it
+        let vary_accept_encoding = response
+            .headers()
+            .get_all(header::VARY)
+            .iter()
+            .any(|value| value.to_str().is_ok_and(|value| value.to_ascii_lowercase().contains("accept-encoding")));
+        if !vary_accept_encoding {
+            response
+                .headers_mut()
+                .append(header::VARY, VARY_ACCEPT_ENCODING_HEADER_VALUE.clone());
+        }
+
+        let compression = &route.config.compression;
+
         // compress as needed
         if let Some(accept_encoding) = route.accept_encoding {
             match accept_encoding {
                 BR_CONTENT_ENCODING => {
+                    let level = compression.br_level;
                     response
                         .headers_mut()
                         .insert(header::CONTENT_ENCODING, BR_HEADER_VALUE.clone());
                     response = response.map(|body| {
                         Body::wrap_stream(brotli_encode(
                             body.map_err(|_| IOError::from(IOErrorKind::InvalidData)),
+                            level,
                         ))
                     });
                 }
                 DEFLATE_CONTENT_ENCODING => {
+                    let level = compression.deflate_level;
                     response
                         .headers_mut()
                         .insert(header::CONTENT_ENCODING, DEFLATE_HEADER_VALUE.clone());
                     response = response.map(|body| {
                         Body::wrap_stream(deflate_encode(
                             body.map_err(|_| IOError::from(IOErrorKind::InvalidData)),
+                            level,
                         ))
                     });
                 }
                 GZIP_CONTENT_ENCODING => {
+                    let level = compression.gzip_level;
                     response
                         .headers_mut()
                         .insert(header::CONTENT_ENCODING, GZIP_HEADER_VALUE.clone());
                     response = response.map(|body| {
                         Body::wrap_stream(gzip_encode(
                             body.map_err(|_| IOError::from(IOErrorKind::InvalidData)),
+                            level,
+                        ))
+                    });
+                }
+                #[cfg(feature = "zstd")]
+                ZSTD_CONTENT_ENCODING => {
+                    let level = compression.zstd_level;
+                    response
+                        .headers_mut()
+                        .insert(header::CONTENT_ENCODING, ZSTD_HEADER_VALUE.clone());
+                    response = response.map(|body| {
+                        Body::wrap_stream(zstd_encode(
+                            body.map_err(|_| IOError::from(IOErrorKind::InvalidData)),
+                            level,
                         ))
                     });
                 }
@@ -251,32 +586,115 @@ impl HttpResponse {
                     // do nothing
                 }
             }
+
+            // the length of the encoded body is not known upfront
+            response.headers_mut().remove(header::CONTENT_LENGTH);
         }
 
         response
     }
+
+    fn is_compressible(route: &HttpRoute<'_>, response: &Response<Body>) -> bool {
+        let compression = &route.config.compression;
+
+        if !compression.enabled
+            || response.extensions().get::<NoCompression>().is_some()
+            || response.headers().contains_key(header::CONTENT_ENCODING)
+            || route.method == Method::HEAD
+        {
+            return false;
+        }
+
+        let status = response.status();
+        if status.is_informational() || status == StatusCode::NO_CONTENT || status == StatusCode::NOT_MODIFIED {
+            return false;
+        }
+
+        // streamed bodies have no exact size, and are compressed whatever their size
+        let body_size = hyper::body::HttpBody::size_hint(response.body()).exact().or_else(|| {
+            response
+                .headers()
+                .get(header::CONTENT_LENGTH)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse::<u64>().ok())
+        });
+        if body_size.is_some_and(|body_size| body_size < compression.min_size as u64) {
+            return false;
+        }
+
+        let content_type = response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok());
+
+        compression.is_compressible_content_type(content_type)
+    }
+}
+
+// in whole seconds, rounded up so that clients do not come back too early
+fn insert_retry_after(response: &mut Response<Body>, retry_after: Option<Duration>) {
+    if let Some(retry_after) = retry_after {
+        let seconds = retry_after.as_millis().div_ceil(1000) as u64;
+        response.headers_mut().insert(header::RETRY_AFTER, HeaderValue::from(seconds));
+    }
+}
+
+fn allow_header(allow: &[Method]) -> String {
+    allow.iter().map(Method::as_str).collect::<Vec<_>>().join(", ")
+}
+
+fn serialise_json_chunk<T, E>(item: Result<T, E>, chunk: &mut Vec<u8>) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+    where
+        T: Serialize,
+        E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let item = item.map_err(Into::into)?;
+    serde_json::to_writer(chunk, &item)?;
+
+    Ok(())
 }
 
 fn gzip_encode(
     input: impl Stream<Item=std::io::Result<bytes::Bytes>>,
+    level: Option<i32>,
 ) -> impl Stream<Item=std::io::Result<bytes::Bytes>> {
-    tokio_util::io::ReaderStream::new(async_compression::tokio::bufread::GzipEncoder::new(
+    tokio_util::io::ReaderStream::new(async_compression::tokio::bufread::GzipEncoder::with_quality(
         tokio_util::io::StreamReader::new(input),
+        compression_level(level),
     ))
 }
 
 fn brotli_encode(
     input: impl Stream<Item=std::io::Result<bytes::Bytes>>,
+    level: Option<i32>,
 ) -> impl Stream<Item=std::io::Result<bytes::Bytes>> {
-    tokio_util::io::ReaderStream::new(async_compression::tokio::bufread::BrotliEncoder::new(
+    tokio_util::io::ReaderStream::new(async_compression::tokio::bufread::BrotliEncoder::with_quality(
         tokio_util::io::StreamReader::new(input),
+        compression_level(level),
     ))
 }
 
 fn deflate_encode(
     input: impl Stream<Item=std::io::Result<bytes::Bytes>>,
+    level: Option<i32>,
 ) -> impl Stream<Item=std::io::Result<bytes::Bytes>> {
-    tokio_util::io::ReaderStream::new(async_compression::tokio::bufread::DeflateEncoder::new(
+    tokio_util::io::ReaderStream::new(async_compression::tokio::bufread::DeflateEncoder::with_quality(
         tokio_util::io::StreamReader::new(input),
+        compression_level(level),
     ))
 }
+
+#[cfg(feature = "zstd")]
+fn zstd_encode(
+    input: impl Stream<Item=std::io::Result<bytes::Bytes>>,
+    level: Option<i32>,
+) -> impl Stream<Item=std::io::Result<bytes::Bytes>> {
+    tokio_util::io::ReaderStream::new(async_compression::tokio::bufread::ZstdEncoder::with_quality(
+        tokio_util::io::StreamReader::new(input),
+        compression_level(level),
+    ))
+}
+
+fn compression_level(level: Option<i32>) -> async_compression::Level {
+    level.map_or(async_compression::Level::Default, async_compression::Level::Precise)
+}
@@ -1,52 +1,131 @@
 use std::net::SocketAddr;
+use std::sync::{Arc, OnceLock};
 use std::time::Instant;
 
 use chrono::Local;
-use http::{header, Method, Request, Uri};
+use http::{header, Method, Request, Uri, Version};
 use hyper::Body;
 
+use crate::server::ServerConfig;
 use crate::server::commons::{BR_CONTENT_ENCODING, DEFLATE_CONTENT_ENCODING, GZIP_CONTENT_ENCODING};
+#[cfg(feature = "zstd")]
+use crate::server::commons::ZSTD_CONTENT_ENCODING;
+use crate::server::negotiation::negotiate_encoding;
+use crate::server::request_context::{request_id, REQUEST_CONTEXT};
+#[cfg(feature = "websocket")]
+use crate::server::websocket::PendingUpgrade;
 
 pub struct HttpRoute<'a> {
     pub req: &'a Request<Body>,
     pub req_time: chrono::DateTime<Local>,
     pub req_instant: Instant,
     pub method: &'a Method,
+    pub version: Version,
     pub uri: &'a Uri,
     pub path: &'a str,
     pub query: Option<&'a str>,
     pub content_encoding: Option<Vec<u8>>,
+    /// Request body codings, lowercased, in the order they were applied. `identity` is left out.
+    pub content_codings: Vec<Vec<u8>>,
     pub accept_encoding: Option<&'a [u8]>,
     pub metric_path: Option<&'static str>,
+    matched_path: OnceLock<&'static str>,
+    /// `Last-Event-ID` sent by a reconnecting Server-Sent Events client.
+    pub last_event_id: Option<&'a str>,
     pub remote_addr: SocketAddr,
+    /// Correlation id of the request, echoed as `X-Request-Id`: the client's `X-Request-Id`, else the trace id of its
+    /// `traceparent`, else a new UUID v4.
+    pub request_id: Arc<str>,
+    pub config: &'a ServerConfig,
+    #[cfg(feature = "websocket")]
+    pub(crate) upgrade: PendingUpgrade,
 }
 
-const CONTENT_ENCODINGS: [&'static [u8]; 3] = [BR_CONTENT_ENCODING, GZIP_CONTENT_ENCODING, DEFLATE_CONTENT_ENCODING];
+/// Metric path of requests that no `Router` route or `TowerMount` prefix matched, so that scanned paths do not each
+/// get their own metrics.
+pub const UNMATCHED_METRIC_PATH: &str = "<unmatched>";
+
+// in order of server preference
+const CONTENT_ENCODINGS: &[&[u8]] = &[
+    #[cfg(feature = "zstd")]
+    ZSTD_CONTENT_ENCODING,
+    BR_CONTENT_ENCODING,
+    GZIP_CONTENT_ENCODING,
+    DEFLATE_CONTENT_ENCODING,
+];
+
+lazy_static! {
+    static ref DEFAULT_SERVER_CONFIG: ServerConfig = ServerConfig::default();
+}
 
 impl<'a> HttpRoute<'a> {
-    pub fn new(req: &'a Request<Body>, req_time: chrono::DateTime<Local>, req_instant: Instant, remote_addr: SocketAddr) -> HttpRoute<'a> {
+    /// Route of a request served with the default `ServerConfig`.
+    pub fn new(
+        req: &'a Request<Body>,
+        req_time: chrono::DateTime<Local>,
+        req_instant: Instant,
+        remote_addr: SocketAddr,
+    ) -> HttpRoute<'a> {
+        HttpRoute::with_config(req, req_time, req_instant, remote_addr, &DEFAULT_SERVER_CONFIG)
+    }
+
+    /// Route of a request served with `config`, whose limits and timeouts then apply to it.
+    pub fn with_config(
+        req: &'a Request<Body>,
+        req_time: chrono::DateTime<Local>,
+        req_instant: Instant,
+        remote_addr: SocketAddr,
+        config: &'a ServerConfig,
+    ) -> HttpRoute<'a> {
         HttpRoute {
             req,
             req_time,
             req_instant,
             method: req.method(),
+            version: req.version(),
             uri: req.uri(),
             path: req.uri().path(),
             query: req.uri().query(),
             content_encoding: req.headers().get(header::CONTENT_ENCODING).map(|value| value.as_bytes().to_ascii_lowercase()),
-            accept_encoding: req.headers().get(header::ACCEPT_ENCODING).and_then(|value| {
-                let accept_encodings = value.as_bytes().to_ascii_lowercase();
-
-                for encoding in CONTENT_ENCODINGS.iter() {
-                    if let Some(_index) = twoway::find_bytes(&accept_encodings, encoding) {
-                        return Some(*encoding);
-                    }
-                }
-
-                None
-            }),
+            content_codings: content_codings(req),
+            accept_encoding: req
+                .headers()
+                .get(header::ACCEPT_ENCODING)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| negotiate_encoding(value, CONTENT_ENCODINGS)),
             metric_path: None,
+            matched_path: OnceLock::new(),
+            last_event_id: req.headers().get("last-event-id").and_then(|value| value.to_str().ok()),
             remote_addr,
+            request_id: REQUEST_CONTEXT
+                .try_with(|context| context.request_id.clone())
+                .unwrap_or_else(|_| request_id(req.headers())),
+            config,
+            #[cfg(feature = "websocket")]
+            upgrade: PendingUpgrade::default(),
         }
     }
+
+    /// Path that metrics are recorded under: `metric_path` when set, else the path given to `set_metric_path`, else
+    /// the raw path.
+    pub fn metric_path(&self) -> &str {
+        self.metric_path.or_else(|| self.matched_path.get().copied()).unwrap_or(self.path)
+    }
+
+    /// Records metrics for this request under `metric_path` instead of its raw path, keeping ids out of metric names.
+    /// Only the first call counts, so the outermost `Router` or `TowerMount` names the request.
+    pub fn set_metric_path(&self, metric_path: &'static str) {
+        let _ = self.matched_path.set(metric_path);
+    }
+}
+
+// the header may be repeated, its values are then read as a single comma separated list
+fn content_codings(req: &Request<Body>) -> Vec<Vec<u8>> {
+    req.headers()
+        .get_all(header::CONTENT_ENCODING)
+        .iter()
+        .flat_map(|value| value.as_bytes().split(|byte| *byte == b','))
+        .map(|coding| coding.trim_ascii().to_ascii_lowercase())
+        .filter(|coding| !coding.is_empty() && coding != b"identity")
+        .collect()
 }
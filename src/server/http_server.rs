@@ -1,32 +1,46 @@
+use std::cell::RefCell;
+use std::future::Future;
 use std::mem;
 use std::net::SocketAddr;
-use std::sync::Arc;
-use std::time::Instant;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Once};
+use std::time::{Duration, Instant};
 
 use anyhow::Context;
 use http::{Method, Request};
 use hyper::Body;
-use hyper::server::conn::AddrStream;
+use hyper::server::accept::Accept;
+use hyper::server::conn::{AddrIncoming, AddrStream};
 use hyper::service::{make_service_fn, service_fn};
 #[allow(unused_imports)]
 use log::{debug, error, info, warn};
+use futures::FutureExt;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::{watch, Semaphore};
 
-use crate::server::{HttpResult, IN_ROTATION, Service, ServiceBuilder, ServiceDaemon, SHUTDOWN};
+use crate::server::{ApiError, HttpProtocol, HttpResult, ServerConfig, IN_ROTATION, Service, ServiceBuilder, ServiceDaemon, SHUTDOWN};
 
+use super::connection::{InFlightBody, ServerIncoming, TrackedConn};
 use super::health_check::{get_in_rotation_status, oor_handler};
 use super::http_response::HttpResponse;
+use super::middleware::Next;
+use super::request_context::{RequestContext, REQUEST_CONTEXT, REQUEST_ID_HEADER};
 use super::HttpRoute;
 #[cfg(any(feature = "access_log", feature = "metrics"))]
 use super::logger;
 #[cfg(feature = "metrics")]
 use super::logger::METRICS_LOGGER;
+#[cfg(feature = "tls")]
+use super::tls::TlsIncoming;
+#[cfg(feature = "websocket")]
+use super::websocket::{is_upgrade_request, PendingUpgrade};
 
 fn index(route: &HttpRoute<'_>) -> HttpResult {
     let body = Body::from("Hello, World!");
     HttpResponse::ok(route, body)
 }
 
-async fn shutdown_signal() {
+async fn shutdown_signal(shutdown_timeout: Option<Duration>, shutdown_sender: watch::Sender<bool>) {
     // Wait for the CTRL+C signal
     info!("Installing server shutdown signal");
 
@@ -38,14 +52,119 @@ async fn shutdown_signal() {
     IN_ROTATION.store(false, std::sync::atomic::Ordering::Relaxed);
 
     warn!("Received server shutdown signal");
+
+    match shutdown_timeout {
+        // stop accepting and let in-flight connections drain, `start_http_server` bounding the wait
+        Some(_) => {
+            let _ = shutdown_sender.send(true);
+        }
+        None => std::process::exit(0),
+    }
+}
+
+// exits the process once `shutdown_timeout` has elapsed since the shutdown signal, unless the servers drained first
+async fn exit_after_shutdown_timeout(shutdown_timeout: Option<Duration>, shutdown_receiver: watch::Receiver<bool>) {
+    let Some(shutdown_timeout) = shutdown_timeout else {
+        return std::future::pending().await;
+    };
+
+    wait_for_shutdown(shutdown_receiver).await;
+    tokio::time::sleep(shutdown_timeout).await;
+
+    warn!("Server shutdown timeout of {:?} elapsed, exiting", shutdown_timeout);
     std::process::exit(0);
 }
 
-// TODO: payload limit - json_payload_limit_conf()
-async fn route_handler<App>(
+async fn wait_for_shutdown(mut shutdown_receiver: watch::Receiver<bool>) {
+    while !*shutdown_receiver.borrow() {
+        if shutdown_receiver.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+// dropping the response future cancels the request, `timeout` running from the arrival of the request
+pub(crate) async fn with_timeout(
+    req_instant: Instant,
+    timeout: Duration,
+    response: impl Future<Output=HttpResult>,
+) -> HttpResult {
+    let deadline = tokio::time::Instant::from_std(req_instant + timeout);
+
+    match tokio::time::timeout_at(deadline, response).await {
+        Ok(response) => response,
+        Err(_) => {
+            #[cfg(feature = "metrics")]
+            METRICS_LOGGER.log_request_timeout();
+
+            Err(ApiError::GatewayTimeout(format!("Request did not complete within {:?}", timeout)))
+        }
+    }
+}
+
+thread_local! {
+    static PANIC_LOCATION: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Lets `catch_panic` tell where a handler panicked, the previous hook still running after it.
+pub(crate) fn install_panic_hook() {
+    static INSTALL: Once = Once::new();
+
+    INSTALL.call_once(|| {
+        let previous_hook = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            let location = info.location().map(|location| location.to_string());
+            PANIC_LOCATION.with(|panic_location| *panic_location.borrow_mut() = location);
+
+            previous_hook(info);
+        }));
+    });
+}
+
+// a panicking handler gets a 500 instead of dropping the connection
+pub(crate) async fn catch_panic(response: impl Future<Output=HttpResult>) -> HttpResult {
+    match AssertUnwindSafe(response).catch_unwind().await {
+        Ok(response) => response,
+        Err(payload) => {
+            let message = payload
+                .downcast_ref::<&str>()
+                .copied()
+                .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+                .unwrap_or("Box<dyn Any>");
+            // the hook ran on this thread, as part of the same poll
+            let location = PANIC_LOCATION
+                .with(|panic_location| panic_location.borrow_mut().take())
+                .unwrap_or_else(|| "unknown location".to_string());
+
+            #[cfg(feature = "metrics")]
+            METRICS_LOGGER.log_panic();
+
+            Err(ApiError::InternalServerError(anyhow::anyhow!("Handler panicked at {}: {}", location, message)))
+        }
+    }
+}
+
+pub(crate) async fn route_handler<App>(
+    req: Request<Body>,
+    remote_addr: SocketAddr,
+    app: Arc<App>,
+    config: Arc<ServerConfig>,
+) -> HttpResult
+    where
+        App: 'static + Service,
+{
+    let context = RequestContext::new(&req, &config);
+
+    REQUEST_CONTEXT
+        .scope(context, serve_request(req, remote_addr, app, config))
+        .await
+}
+
+async fn serve_request<App>(
     mut req: Request<Body>,
     remote_addr: SocketAddr,
     app: Arc<App>,
+    config: Arc<ServerConfig>,
 ) -> HttpResult
     where
         App: 'static + Service,
@@ -54,7 +173,15 @@ async fn route_handler<App>(
     let req_instant = Instant::now();
 
     let req_body = mem::replace(req.body_mut(), Body::empty());
-    let route = HttpRoute::new(&req, req_time, req_instant, remote_addr);
+    #[cfg(feature = "websocket")]
+    let upgrade = is_upgrade_request(req.headers()).then(|| hyper::upgrade::on(&mut req));
+
+    #[allow(unused_mut)]
+    let mut route = HttpRoute::with_config(&req, req_time, req_instant, remote_addr, &config);
+    #[cfg(feature = "websocket")]
+    {
+        route.upgrade = PendingUpgrade::new(upgrade);
+    }
 
     let parts: Vec<_> = route
         .path
@@ -62,54 +189,136 @@ async fn route_handler<App>(
         .filter(|part| !part.is_empty())
         .collect();
 
+    let builtin_routes = &config.builtin_routes;
     let response = match &parts[..] {
-        [] if matches!(route.method, &Method::GET) => index(&route),
-        ["oor"] => oor_handler(&route),
-        ["health"] if matches!(route.method, &Method::GET) => get_in_rotation_status(&route),
+        [] if builtin_routes.index && matches!(route.method, &Method::GET) => index(&route),
+        ["oor"] if builtin_routes.oor => oor_handler(&route),
+        ["health"] if builtin_routes.health && matches!(route.method, &Method::GET) => get_in_rotation_status(&route),
 
         #[cfg(feature = "metrics")]
-        ["metrics", rest @ ..] => METRICS_LOGGER.api_handler(req_body, &route, rest).await,
+        ["metrics", rest @ ..] if builtin_routes.metrics => METRICS_LOGGER.api_handler(req_body, &route, rest).await,
 
-        ["api", rest @ ..] => app.api_handler(req_body, &route, rest).await,
+        // the chain answers the service's panics and timeouts itself, panicking middlewares are caught here. Its deadline
+        // is the service's, which is polled first, so the middlewares see the service time out but cannot outlast it
+        ["api", rest @ ..] => {
+            let response = catch_panic(Next::new(app.middlewares(), &*app, rest).run(req_body, &route).map(Ok));
+            match config.request_timeout_for(route.path) {
+                Some(timeout) => with_timeout(req_instant, timeout, response).await,
+                None => response.await,
+            }
+        }
         _ => HttpResponse::not_found(route.path),
     };
 
+    // errors get their final response first, so that the time header, the access log and metrics cover them too
+    #[allow(unused_mut)]
+    let mut response = response.unwrap_or_else(ApiError::into_response);
+
+    if let Ok(request_id) = http::HeaderValue::from_str(&route.request_id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, request_id);
+    }
+
     #[cfg(feature = "response_time")]
-        let response = match response {
-        Ok(mut response) => {
-            let time_taken = format!("{}", humantime::Duration::from(req_instant.elapsed()));
-            let time_taken_header = http::HeaderValue::from_str(&time_taken)
-                .with_context(|| format!("Error in building header value time_taken"))?;
+    {
+        let time_taken = format!("{}", humantime::Duration::from(req_instant.elapsed()));
+        if let Ok(time_taken_header) = http::HeaderValue::from_str(&time_taken) {
             response
                 .headers_mut()
                 .append("X-time-taken", time_taken_header);
-            Ok(response)
         }
-        Err(err) => err.into(),
-    };
+    }
 
     // log & metrics
     #[cfg(any(feature = "access_log", feature = "metrics"))]
     logger::log_api(&route, &response);
 
-    response
+    Ok(response)
 }
 
-pub async fn start_http_server<App, AppDaemon, AppBuilder>(
-    addr: &str,
+pub async fn start_http_server<Config, App, AppDaemon, AppBuilder>(
+    config: Config,
     app_builder: AppBuilder,
 ) -> anyhow::Result<()>
     where
+        Config: TryInto<ServerConfig>,
+        Config::Error: Into<anyhow::Error>,
         App: 'static + Service,
         AppDaemon: 'static + ServiceDaemon<App>,
         AppBuilder: 'static + ServiceBuilder<App, AppDaemon>,
 {
-    info!("Starting server at addr: {}", addr);
+    let config = config
+        .try_into()
+        .map_err(Into::into)
+        .with_context(|| "Error in loading server config")?;
+    let config = Arc::new(config);
+    config.validate().with_context(|| "Error in validating server config")?;
+    install_panic_hook();
+
+    info!("Starting server at addrs: {:?}", config.addrs);
+
+    let addrs = config
+        .addrs
+        .iter()
+        .map(|addr| parse_addr(addr))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    #[cfg(feature = "tls")]
+    let tls_acceptor = match &config.tls {
+        Some(tls_config) => Some(
+            tls_config
+                .build_acceptor(config.http2.protocol)
+                .with_context(|| format!("Error in loading TLS config: {:?}", tls_config))?,
+        ),
+        None => None,
+    };
 
-    let addr = addr
-        .parse::<SocketAddr>()
-        .with_context(|| format!("Parsing node addr '{}' as SocketAddr", addr))?;
+    let app = build_app(app_builder).await?;
+
+    let connection_limit = config.max_connections.map(|max_connections| Arc::new(Semaphore::new(max_connections)));
+    let (shutdown_sender, shutdown_receiver) = watch::channel(false);
+    tokio::task::spawn(shutdown_signal(config.shutdown_timeout, shutdown_sender));
+
+    let mut servers = Vec::with_capacity(addrs.len());
+    for addr in addrs {
+        let incoming = AddrIncoming::bind(&addr)
+            .with_context(|| format!("Error in binding to address: {}", addr))?;
+
+        let shutdown = wait_for_shutdown(shutdown_receiver.clone());
+
+        let incoming = ServerIncoming::new(incoming, connection_limit.clone(), config.keep_alive_timeout);
+
+        #[cfg(feature = "tls")]
+        if let Some(tls_acceptor) = &tls_acceptor {
+            let incoming = TlsIncoming::new(incoming, tls_acceptor.clone());
+            servers.push(serve(incoming, config.clone(), app.clone(), shutdown).boxed());
+            continue;
+        }
 
+        servers.push(serve(incoming, config.clone(), app.clone(), shutdown).boxed());
+    }
+
+    tokio::select! {
+        result = futures::future::try_join_all(servers) => {
+            result?;
+            info!("Server shut down");
+        }
+        () = exit_after_shutdown_timeout(config.shutdown_timeout, shutdown_receiver) => {}
+    }
+
+    Ok(())
+}
+
+fn parse_addr(addr: &str) -> anyhow::Result<SocketAddr> {
+    addr.parse::<SocketAddr>()
+        .with_context(|| format!("Parsing node addr '{}' as SocketAddr", addr))
+}
+
+async fn build_app<App, AppDaemon, AppBuilder>(app_builder: AppBuilder) -> anyhow::Result<Arc<App>>
+    where
+        App: 'static + Service,
+        AppDaemon: 'static + ServiceDaemon<App>,
+        AppBuilder: 'static + ServiceBuilder<App, AppDaemon>,
+{
     let (app, app_daemon) = app_builder
         .build()
         .await
@@ -124,30 +333,81 @@ pub async fn start_http_server<App, AppDaemon, AppBuilder>(
         });
     }
 
-    let make_svc = make_service_fn(move |transport: &AddrStream| {
+    Ok(app)
+}
+
+async fn serve<I, App>(incoming: I, config: Arc<ServerConfig>, app: Arc<App>, shutdown: impl Future<Output=()>) -> anyhow::Result<()>
+    where
+        I: Accept + Unpin,
+        I::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+        I::Conn: RemoteAddr + TrackedConn + AsyncRead + AsyncWrite + Unpin + Send + 'static,
+        App: 'static + Service,
+{
+    let http2_config = &config.http2;
+    let mut builder = hyper::Server::builder(incoming)
+        .http1_keepalive(config.keep_alive)
+        .http1_preserve_header_case(true)
+        .http1_title_case_headers(true)
+        .http1_only(http2_config.protocol == HttpProtocol::Http1)
+        .http2_only(http2_config.protocol == HttpProtocol::Http2)
+        .http2_max_concurrent_streams(http2_config.max_concurrent_streams)
+        .http2_initial_stream_window_size(http2_config.initial_stream_window_size)
+        .http2_initial_connection_window_size(http2_config.initial_connection_window_size)
+        .http2_adaptive_window(http2_config.adaptive_window)
+        .http2_max_frame_size(http2_config.max_frame_size)
+        .http2_keep_alive_interval(http2_config.keep_alive_interval)
+        .http2_keep_alive_timeout(http2_config.keep_alive_timeout);
+
+    if let Some(header_read_timeout) = config.header_read_timeout {
+        builder = builder.http1_header_read_timeout(header_read_timeout);
+    }
+
+    if let Some(max_header_size) = config.max_header_size {
+        builder = builder
+            .http1_max_buf_size(max_header_size)
+            .http2_max_header_list_size(max_header_size as u32);
+    }
+
+    let make_svc = make_service_fn(move |transport: &I::Conn| {
         // TODO: log new connection
         let remote_addr = transport.remote_addr();
+        let activity = transport.activity();
         let app = app.clone();
+        let config = config.clone();
 
         async move {
-            Ok::<_, anyhow::Error>(service_fn(move |req| {
+            Ok::<_, anyhow::Error>(service_fn(move |mut req: Request<Body>| {
+                let in_flight = activity.start_request();
+                // lets an upgraded connection count as busy for as long as it is in use
+                req.extensions_mut().insert(activity.clone());
                 // Clone again to ensure that client outlives this closure.
-                route_handler(req, remote_addr, app.clone())
+                let response = route_handler(req, remote_addr, app.clone(), config.clone());
+
+                async move {
+                    let response = response.await?;
+                    Ok::<_, ApiError>(response.map(|body| InFlightBody::new(body, in_flight)))
+                }
             }))
         }
     });
 
-    let server = hyper::Server::try_bind(&addr)
-        .with_context(|| "Error in binding to address")?
-        .http1_keepalive(true)
-        .http1_preserve_header_case(true)
-        .http1_title_case_headers(true)
-        .serve(make_svc);
+    let server = builder.serve(make_svc);
 
-    let graceful = server.with_graceful_shutdown(shutdown_signal());
+    let graceful = server.with_graceful_shutdown(shutdown);
 
     info!("Started server");
 
     // Run this server for... forever!
     graceful.await.with_context(|| "Error in starting server")
 }
+
+/// Gives the peer address of an accepted connection, for both plain and TLS transports.
+pub(crate) trait RemoteAddr {
+    fn remote_addr(&self) -> SocketAddr;
+}
+
+impl RemoteAddr for AddrStream {
+    fn remote_addr(&self) -> SocketAddr {
+        AddrStream::remote_addr(self)
+    }
+}
@@ -29,7 +29,9 @@ pub fn log(route: &HttpRoute<'_>, response: &Response<Body>, elapsed_time: &Dura
     // RequestContentType
     // RequestContentEncoding
     // RequestAcceptEncoding
-    info!(target: "access_log", "{} {} {} {:.6} {:?} {:?} {:?} {} {:?} {:?} {:?} {:?} {:?}",
+    // HttpVersion
+    // RequestId
+    info!(target: "access_log", "{} {} {} {:.6} {:?} {:?} {:?} {} {:?} {:?} {:?} {:?} {:?} {:?} {}",
                       route.remote_addr.ip().to_string(),
                       route.req_time.to_rfc3339(),
                       response_status,
@@ -43,5 +45,7 @@ pub fn log(route: &HttpRoute<'_>, response: &Response<Body>, elapsed_time: &Dura
                       route.req.headers().get(header::CONTENT_TYPE).unwrap_or_else(|| &EMPTY_HEADER_VALUE),
                       route.req.headers().get(header::CONTENT_ENCODING).unwrap_or_else(|| &EMPTY_HEADER_VALUE),
                       route.req.headers().get(header::ACCEPT_ENCODING).unwrap_or_else(|| &EMPTY_HEADER_VALUE),
+                      route.version,
+                      route.request_id,
                 );
 }
\ No newline at end of file
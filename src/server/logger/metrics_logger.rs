@@ -7,6 +7,7 @@ use crossbeam_skiplist::SkipList;
 use http::Response;
 use hyper::Body;
 use metered::{HitCount, measure};
+use metered::atomic::AtomicInt;
 use prometheus::{Encoder, Opts, Registry};
 use serde::{Serialize, Serializer};
 use serde::ser::{SerializeMap, SerializeSeq};
@@ -23,6 +24,40 @@ lazy_static! {
 
 pub struct MetricsLogger {
     registry: MetricsRegistry,
+    counters: ServerCounters,
+}
+
+/// Server wide counters, that are not tied to an api path.
+#[derive(Default, Serialize)]
+struct ServerCounters {
+    decompression_limit_exceeded: AtomicInt<u64>,
+    request_timeouts: AtomicInt<u64>,
+    panics: AtomicInt<u64>,
+    sse_streams_opened: AtomicInt<u64>,
+    sse_streams_open: AtomicInt<u64>,
+    websockets_opened: AtomicInt<u64>,
+    websockets_open: AtomicInt<u64>,
+}
+
+impl ServerCounters {
+    // name, help, value
+    fn values(&self) -> Vec<(&'static str, &'static str, u64)> {
+        vec![
+            ("decompression_limit_exceeded", "requests rejected for exceeding the decompressed size or ratio limit", self.decompression_limit_exceeded.get()),
+            ("request_timeouts", "requests cancelled for exceeding their timeout", self.request_timeouts.get()),
+            ("panics", "requests whose handler panicked", self.panics.get()),
+            ("sse_streams_opened", "server-sent event streams opened", self.sse_streams_opened.get()),
+            ("websockets_opened", "websocket connections opened", self.websockets_opened.get()),
+        ]
+    }
+
+    // name, help, value
+    fn gauges(&self) -> Vec<(&'static str, &'static str, u64)> {
+        vec![
+            ("sse_streams_open", "server-sent event streams currently open", self.sse_streams_open.get()),
+            ("websockets_open", "websocket connections currently open", self.websockets_open.get()),
+        ]
+    }
 }
 
 struct MetricsRegistry {
@@ -32,6 +67,7 @@ struct MetricsRegistry {
 struct Metrics {
     path: String,
     method: String,
+    version: String,
     code: u16,
     hits: HitCount,
     errors: ErrorCounter,
@@ -44,13 +80,47 @@ impl MetricsLogger {
             registry: MetricsRegistry {
                 metrics: SkipList::new(epoch::default_collector().clone()),
             },
+            counters: ServerCounters::default(),
         }
     }
 
+    pub fn log_decompression_limit_exceeded(&self) {
+        self.counters.decompression_limit_exceeded.incr();
+    }
+
+    pub fn log_request_timeout(&self) {
+        self.counters.request_timeouts.incr();
+    }
+
+    pub fn log_panic(&self) {
+        self.counters.panics.incr();
+    }
+
+    pub fn log_sse_stream_opened(&self) {
+        self.counters.sse_streams_opened.incr();
+        self.counters.sse_streams_open.incr();
+    }
+
+    pub fn log_sse_stream_closed(&self) {
+        self.counters.sse_streams_open.decr();
+    }
+
+    #[cfg(feature = "websocket")]
+    pub fn log_websocket_opened(&self) {
+        self.counters.websockets_opened.incr();
+        self.counters.websockets_open.incr();
+    }
+
+    #[cfg(feature = "websocket")]
+    pub fn log_websocket_closed(&self) {
+        self.counters.websockets_open.decr();
+    }
+
     pub fn log(&self, route: &HttpRoute<'_>, response: &Response<Body>, elapsed_time: &Duration) {
-        let path = route.metric_path.unwrap_or_else(|| route.path);
+        let path = route.metric_path();
         let code = response.status().as_u16();
-        let metric_label = format!("{}/{}/{}", path, route.method, code);
+        let version = format!("{:?}", route.version);
+        let metric_label = format!("{}/{}/{}/{}", path, route.method, version, code);
 
         let guard = &epoch::pin();
         let api_metrics_entry = self.registry.metrics.get_or_insert_with(
@@ -58,6 +128,7 @@ impl MetricsLogger {
             || Metrics {
                 path: path.to_string(),
                 method: route.method.to_string(),
+                version,
                 code,
                 hits: Default::default(),
                 errors: Default::default(),
@@ -74,19 +145,20 @@ impl MetricsLogger {
         let hits = &api_metrics.hits;
         measure!(hits, {});
 
-        if !response.status().is_success() {
+        // informational responses, e.g. `101 Switching Protocols`, and redirects are not errors
+        if response.status().is_client_error() || response.status().is_server_error() {
             api_metrics.errors.increment_by(1);
         }
 
         api_metrics_entry.release(guard);
     }
 
-    // labels: code, method, path ==> hits, errors, response time
+    // labels: code, method, version, path ==> hits, errors, response time
     pub async fn get_api_metrics_for_prometheus(&self, route: &HttpRoute<'_>) -> HttpResult {
         let registry = Registry::new();
 
         // register 3 counters vector... hits, errors, quantiles... label being ["path", "method", "code"]
-        let labels = vec!["path", "method", "code"];
+        let labels = vec!["path", "method", "version", "code"];
         let hits_counter_opts = Opts::new("hits", "hits counter");
         let hits_counter = prometheus::CounterVec::new(hits_counter_opts, &labels)
             .with_context(|| format!("Error in building hits counter"))?;
@@ -102,13 +174,31 @@ impl MetricsLogger {
             .with_context(|| format!("Error in registering errors counter"))?;
 
         let quantile_counter_opts = Opts::new("quantiles", "quantiles counter");
-        let labels = vec!["path", "method", "code", "quantile"];
+        let labels = vec!["path", "method", "version", "code", "quantile"];
         let quantiles_counter = prometheus::CounterVec::new(quantile_counter_opts, &labels)
             .with_context(|| format!("Error in building quantiles counter"))?;
         registry
             .register(Box::new(quantiles_counter.clone()))
             .with_context(|| format!("Error in registering quantiles counter"))?;
 
+        for (name, help, value) in self.counters.values() {
+            let counter = prometheus::Counter::with_opts(Opts::new(name, help))
+                .with_context(|| format!("Error in building {} counter", name))?;
+            registry
+                .register(Box::new(counter.clone()))
+                .with_context(|| format!("Error in registering {} counter", name))?;
+            counter.inc_by(value as f64);
+        }
+
+        for (name, help, value) in self.counters.gauges() {
+            let gauge = prometheus::Gauge::with_opts(Opts::new(name, help))
+                .with_context(|| format!("Error in building {} gauge", name))?;
+            registry
+                .register(Box::new(gauge.clone()))
+                .with_context(|| format!("Error in registering {} gauge", name))?;
+            gauge.set(value as f64);
+        }
+
         // iterate over registry and serialize
         let guard = &epoch::pin();
         for entry in self.registry.metrics.iter(guard) {
@@ -116,17 +206,17 @@ impl MetricsLogger {
 
             let code = format!("{}", api_metrics.code);
             hits_counter
-                .with_label_values(&[&api_metrics.path, &api_metrics.method, &code])
+                .with_label_values(&[&api_metrics.path, &api_metrics.method, &api_metrics.version, &code])
                 .inc_by(api_metrics.hits.0.get() as f64);
 
             errors_counter
-                .with_label_values(&[&api_metrics.path, &api_metrics.method, &code])
+                .with_label_values(&[&api_metrics.path, &api_metrics.method, &api_metrics.version, &code])
                 .inc_by(api_metrics.errors.0.get() as f64);
 
             let percentile_map = api_metrics.response_time.get_percentile_map()?;
             for (metric, value) in percentile_map {
                 quantiles_counter
-                    .with_label_values(&[&api_metrics.path, &api_metrics.method, &code, &metric])
+                    .with_label_values(&[&api_metrics.path, &api_metrics.method, &api_metrics.version, &code, &metric])
                     .inc_by(value as f64);
             }
         }
@@ -144,6 +234,10 @@ impl MetricsLogger {
     pub async fn get_api_metrics_as_json(&self, route: &HttpRoute<'_>) -> HttpResult {
         HttpResponse::json(route, &self.registry)
     }
+
+    pub async fn get_server_counters_as_json(&self, route: &HttpRoute<'_>) -> HttpResult {
+        HttpResponse::json(route, &self.counters)
+    }
 }
 
 impl Serialize for Metrics {
@@ -154,6 +248,7 @@ impl Serialize for Metrics {
         let mut map = serializer.serialize_map(Some(3))?;
         map.serialize_entry("path", &self.path)?;
         map.serialize_entry("method", &self.method)?;
+        map.serialize_entry("version", &self.version)?;
         map.serialize_entry("code", &self.code)?;
         map.serialize_entry("hit_count", &self.hits.0.get())?;
         map.serialize_entry("error_count", &self.errors.0.get())?;
@@ -194,6 +289,8 @@ impl Service for MetricsLogger {
 
             ["prometheus"] if matches!(route.method, &http::Method::GET) => self.get_api_metrics_for_prometheus(route).await,
 
+            ["counters"] if matches!(route.method, &http::Method::GET) => self.get_server_counters_as_json(route).await,
+
             _ => HttpResponse::not_found(route.path),
         }
     }
@@ -1,11 +1,19 @@
 #[cfg(feature = "metrics")]
 pub use metrics_logger::METRICS_LOGGER;
+#[cfg(feature = "access_log")]
+pub(crate) use request_id_encoder::RequestIdEncoderDeserializer;
+
+use http::Response;
+use hyper::Body;
 
-use crate::server::{HttpResult, HttpRoute};
+use crate::server::HttpRoute;
 
 #[cfg(feature = "access_log")]
 mod access_logger;
 
+#[cfg(feature = "access_log")]
+mod request_id_encoder;
+
 #[cfg(feature = "metrics")]
 mod metrics;
 
@@ -15,17 +23,14 @@ mod response_time;
 #[cfg(feature = "metrics")]
 mod metrics_logger;
 
-pub fn log_api(route: &HttpRoute<'_>, response: &HttpResult) {
-    #[cfg(any(feature = "access_log", feature = "metrics"))]
-    if let Ok(response) = response {
-        let elapsed_time = route.req_instant.elapsed();
+pub fn log_api(route: &HttpRoute<'_>, response: &Response<Body>) {
+    let elapsed_time = route.req_instant.elapsed();
 
-        #[cfg(feature = "access_log")]
-        access_logger::log(route, response, &elapsed_time);
+    #[cfg(feature = "access_log")]
+    access_logger::log(route, response, &elapsed_time);
 
-        #[cfg(feature = "metrics")]
-        METRICS_LOGGER.log(route, response, &elapsed_time);
-    }
+    #[cfg(feature = "metrics")]
+    METRICS_LOGGER.log(route, response, &elapsed_time);
 }
 
 
@@ -0,0 +1,50 @@
+use log::Record;
+use log4rs::config::{Deserialize, Deserializers};
+use log4rs::encode::pattern::{PatternEncoderConfig, PatternEncoderDeserializer};
+use log4rs::encode::{Encode, Write};
+
+use crate::server::request_context::current_request_id;
+
+// access log lines already end with the request id
+const ACCESS_LOG_TARGET: &str = "access_log";
+
+/// Pattern encoder prefixing the message of records logged while serving a request with the request id, e.g.
+/// `[4bf92f3577b34da6a3ce929d0e0e4736] Error in ..`.
+#[derive(Debug)]
+struct RequestIdEncoder(Box<dyn Encode>);
+
+impl Encode for RequestIdEncoder {
+    fn encode(&self, w: &mut dyn Write, record: &Record) -> anyhow::Result<()> {
+        let request_id = match current_request_id() {
+            Some(request_id) if record.target() != ACCESS_LOG_TARGET => request_id,
+            _ => return self.0.encode(w, record),
+        };
+
+        self.0.encode(
+            w,
+            &Record::builder()
+                .args(format_args!("[{}] {}", request_id, record.args()))
+                .level(record.level())
+                .target(record.target())
+                .module_path(record.module_path())
+                .file(record.file())
+                .line(record.line())
+                .build(),
+        )
+    }
+}
+
+/// Deserializes `pattern` encoders of log4rs config files as `RequestIdEncoder`s.
+pub(crate) struct RequestIdEncoderDeserializer;
+
+impl Deserialize for RequestIdEncoderDeserializer {
+    type Trait = dyn Encode;
+
+    type Config = PatternEncoderConfig;
+
+    fn deserialize(&self, config: PatternEncoderConfig, deserializers: &Deserializers) -> anyhow::Result<Box<dyn Encode>> {
+        let encoder = PatternEncoderDeserializer.deserialize(config, deserializers)?;
+
+        Ok(Box::new(RequestIdEncoder(encoder)))
+    }
+}
@@ -0,0 +1,66 @@
+use async_trait::async_trait;
+use http::Response;
+use hyper::Body;
+
+use super::http_server::{catch_panic, with_timeout};
+use super::{ApiError, HttpResult, HttpRoute, Service};
+
+/// Runs around `Service::api_handler`, seeing the request before the service and its response after it, e.g.
+///
+/// ```ignore
+/// #[async_trait]
+/// impl Middleware for AllowAnyOrigin {
+///     async fn handle<'a>(&'a self, body: Body, route: &HttpRoute<'a>, next: Next<'a>) -> HttpResult {
+///         let mut response = next.run(body, route).await;
+///         response.headers_mut().insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue::from_static("*"));
+///         Ok(response)
+///     }
+/// }
+/// ```
+///
+/// Answering without calling `next` short-circuits the middlewares after it and the service. `Next::run` turns the
+/// errors of the rest of the chain into responses, so that a middleware sees every response after it, handler errors,
+/// panics and timeouts included. Middlewares share the request timeout with the service, and are cancelled with it.
+#[async_trait]
+pub trait Middleware: Send + Sync {
+    async fn handle<'a>(&'a self, body: Body, route: &HttpRoute<'a>, next: Next<'a>) -> HttpResult;
+}
+
+/// The rest of the chain: the middlewares after the current one, then the service.
+pub struct Next<'a> {
+    middlewares: &'a [Box<dyn Middleware>],
+    service: &'a dyn Service,
+    path: &'a [&'a str],
+}
+
+impl<'a> Next<'a> {
+    pub(crate) fn new(middlewares: &'a [Box<dyn Middleware>], service: &'a dyn Service, path: &'a [&'a str]) -> Next<'a> {
+        Next {
+            middlewares,
+            service,
+            path,
+        }
+    }
+
+    pub async fn run(self, body: Body, route: &HttpRoute<'a>) -> Response<Body> {
+        let response = match self.middlewares.split_first() {
+            Some((middleware, middlewares)) => {
+                let next = Next {
+                    middlewares,
+                    ..self
+                };
+                middleware.handle(body, route, next).await
+            }
+            None => {
+                // the service gets its own timeout, so that the middlewares still see its response
+                let response = catch_panic(self.service.api_handler(body, route, self.path));
+                match route.config.request_timeout_for(route.path) {
+                    Some(timeout) => with_timeout(route.req_instant, timeout, response).await,
+                    None => response.await,
+                }
+            }
+        };
+
+        response.unwrap_or_else(ApiError::into_response)
+    }
+}
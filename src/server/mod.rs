@@ -1,13 +1,26 @@
 use hyper::Body;
 use hyper::Response;
 
+pub use config::{BuiltinRoutes, CompressionConfig, Http2Config, HttpProtocol, RouteTimeout, ServerConfig};
 pub use error::ApiError;
 pub use http_request::HttpRequest;
-pub use http_response::HttpResponse;
-pub use http_route::HttpRoute;
+pub use http_response::{HttpResponse, NoCompression};
+pub use http_route::{HttpRoute, UNMATCHED_METRIC_PATH};
 pub use http_server::start_http_server;
+pub use middleware::{Middleware, Next};
+pub use request_context::current_request_id;
 // pub(crate) use logger::ACCESS_LOGGER;
+pub use router::{PathParams, Router};
+pub use sse::SseEvent;
 pub use service::{IN_ROTATION, Service, ServiceBuilder, ServiceDaemon, SHUTDOWN};
+#[cfg(feature = "tls")]
+pub use tls::TlsConfig;
+#[cfg(feature = "websocket")]
+pub use config::WebSocketConfig;
+#[cfg(feature = "websocket")]
+pub use websocket::{CloseCode, CloseFrame, Message, WebSocket, WebSocketError};
+#[cfg(feature = "tower")]
+pub use self::tower::{TowerAdapter, TowerMount};
 
 pub type ApiResult<R> = Result<R, ApiError>;
 pub type HttpResult = Result<Response<Body>, ApiError>;
@@ -15,16 +28,35 @@ pub type HttpResult = Result<Response<Body>, ApiError>;
 #[cfg(any(feature = "access_log", feature = "metrics"))]
 mod logger;
 
+mod codec;
 mod commons;
+mod config;
+mod connection;
 mod error;
 mod health_check;
 mod http_request;
 mod http_response;
 mod http_route;
 mod http_server;
+mod middleware;
+mod negotiation;
+#[cfg(feature = "websocket")]
+mod permessage_deflate;
+mod request_context;
+mod router;
 mod service;
+mod sse;
 
 #[cfg(feature = "settings")]
 mod settings;
 
+#[cfg(feature = "tls")]
+mod tls;
+
+#[cfg(feature = "tower")]
+mod tower;
+
+#[cfg(feature = "websocket")]
+mod websocket;
+
 pub mod utils;
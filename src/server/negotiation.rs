@@ -0,0 +1,168 @@
+/// A single element of a header like `Accept-Encoding: gzip;q=0.8, br`, with its quality in thousandths.
+pub(crate) struct QualityItem<'a> {
+    pub value: &'a str,
+    pub quality: u16,
+}
+
+/// Parses a comma separated list of values with optional `q` weights (RFC 9110, section 12.4.2).
+/// Items with a malformed weight are skipped.
+pub(crate) fn parse_quality_list(header: &str) -> Vec<QualityItem<'_>> {
+    header
+        .split(',')
+        .filter_map(|item| {
+            let mut params = item.split(';');
+            let value = params.next()?.trim();
+            if value.is_empty() {
+                return None;
+            }
+
+            let mut quality = 1000;
+            for param in params {
+                if let Some((name, weight)) = param.split_once('=') {
+                    if name.trim().eq_ignore_ascii_case("q") {
+                        quality = parse_quality(weight.trim())?;
+                    }
+                }
+            }
+
+            Some(QualityItem { value, quality })
+        })
+        .collect()
+}
+
+// qvalue = ( "0" [ "." 0*3DIGIT ] ) / ( "1" [ "." 0*3("0") ] )
+fn parse_quality(weight: &str) -> Option<u16> {
+    let (integer, fraction) = weight.split_once('.').unwrap_or((weight, ""));
+    if fraction.len() > 3 || !fraction.bytes().all(|digit| digit.is_ascii_digit()) {
+        return None;
+    }
+
+    let fraction = fraction.bytes().chain(std::iter::repeat(b'0')).take(3).fold(0u16, |value, digit| value * 10 + (digit - b'0') as u16);
+
+    match integer {
+        "0" => Some(fraction),
+        "1" if fraction == 0 => Some(1000),
+        _ => None,
+    }
+}
+
+/// Picks the response content coding for an `Accept-Encoding` header (RFC 9110, section 12.5.3).
+///
+/// `supported` lists the codings the server can produce, in order of preference, which breaks ties between equally
+/// weighted codings. `None` means the response is sent as is (`identity`).
+pub(crate) fn negotiate_encoding(accept_encoding: &str, supported: &[&'static [u8]]) -> Option<&'static [u8]> {
+    let items = parse_quality_list(accept_encoding);
+
+    let quality_of = |coding: &str| -> Option<u16> {
+        items
+            .iter()
+            .find(|item| item.value.eq_ignore_ascii_case(coding))
+            .map(|item| item.quality)
+    };
+    let wildcard_quality = quality_of("*");
+
+    // identity is always acceptable, unless excluded explicitly or through "*;q=0"
+    let identity_quality = quality_of("identity").or(wildcard_quality).unwrap_or(1);
+
+    let mut best: Option<(&'static [u8], u16)> = None;
+    for coding in supported {
+        let quality = std::str::from_utf8(coding)
+            .ok()
+            .and_then(&quality_of)
+            .or(wildcard_quality)
+            .unwrap_or(0);
+
+        if quality > 0 && best.is_none_or(|(_, best_quality)| quality > best_quality) {
+            best = Some((coding, quality));
+        }
+    }
+
+    match best {
+        Some((coding, quality)) if quality >= identity_quality => Some(coding),
+        _ => None,
+    }
+}
+
+/// Quality given to `media_type` by the media ranges of an `Accept` header (RFC 9110, section 12.5.1), taken from the
+/// most specific range that matches it: `type/subtype`, then `type/*`, then `*/*`.
+pub(crate) fn media_type_quality(accept: &[QualityItem<'_>], media_type: &str) -> Option<u16> {
+    let (type_, _) = media_type.split_once('/')?;
+
+    let mut best: Option<(u8, u16)> = None;
+    for item in accept {
+        let (range_type, range_subtype) = match item.value.split_once('/') {
+            Some(range) => range,
+            None => continue,
+        };
+
+        let specificity = if item.value.eq_ignore_ascii_case(media_type) {
+            2
+        } else if range_subtype == "*" && range_type.eq_ignore_ascii_case(type_) {
+            1
+        } else if range_type == "*" && range_subtype == "*" {
+            0
+        } else {
+            continue;
+        };
+
+        if best.is_none_or(|(best_specificity, _)| specificity > best_specificity) {
+            best = Some((specificity, item.quality));
+        }
+    }
+
+    best.map(|(_, quality)| quality)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::server::commons::{BR_CONTENT_ENCODING, DEFLATE_CONTENT_ENCODING, GZIP_CONTENT_ENCODING};
+
+    use super::*;
+
+    const SUPPORTED: [&[u8]; 3] = [BR_CONTENT_ENCODING, GZIP_CONTENT_ENCODING, DEFLATE_CONTENT_ENCODING];
+
+    fn values(header: &str) -> Vec<(&str, u16)> {
+        parse_quality_list(header).into_iter().map(|item| (item.value, item.quality)).collect()
+    }
+
+    #[test]
+    fn parses_quality_values() {
+        assert_eq!(values("gzip, br;q=0.5, deflate;q=0"), [("gzip", 1000), ("br", 500), ("deflate", 0)]);
+        assert_eq!(values("gzip;Q=1.000, br ; q=0.001"), [("gzip", 1000), ("br", 1)]);
+        assert_eq!(values("gzip;level=1;q=0.25"), [("gzip", 250)]);
+        assert_eq!(values(" , gzip,,"), [("gzip", 1000)]);
+    }
+
+    #[test]
+    fn skips_malformed_quality_values() {
+        let header = "a;q=2, b;q=1.001, c;q=0.1234, d;q=abc, e;q=, f;q=.5, g;q=-0, h;q=0.5";
+
+        assert_eq!(values(header), [("h", 500)]);
+    }
+
+    #[test]
+    fn excludes_codings_with_zero_quality() {
+        assert_eq!(negotiate_encoding("br;q=0, gzip", &SUPPORTED), Some(GZIP_CONTENT_ENCODING));
+        assert_eq!(negotiate_encoding("br;q=0", &SUPPORTED), None);
+        assert_eq!(negotiate_encoding("*;q=0", &SUPPORTED), None);
+        assert_eq!(negotiate_encoding("deflate, *;q=0", &SUPPORTED), Some(DEFLATE_CONTENT_ENCODING));
+        assert_eq!(negotiate_encoding("*, br;q=0", &SUPPORTED), Some(GZIP_CONTENT_ENCODING));
+    }
+
+    #[test]
+    fn compares_codings_with_identity() {
+        assert_eq!(negotiate_encoding("identity, gzip;q=0.5", &SUPPORTED), None);
+        assert_eq!(negotiate_encoding("identity;q=0.5, gzip", &SUPPORTED), Some(GZIP_CONTENT_ENCODING));
+        assert_eq!(negotiate_encoding("identity;q=0, gzip;q=0.1", &SUPPORTED), Some(GZIP_CONTENT_ENCODING));
+        assert_eq!(negotiate_encoding("identity;q=0", &SUPPORTED), None);
+        assert_eq!(negotiate_encoding("", &SUPPORTED), None);
+    }
+
+    #[test]
+    fn breaks_ties_by_server_preference() {
+        assert_eq!(negotiate_encoding("gzip, br", &SUPPORTED), Some(BR_CONTENT_ENCODING));
+        assert_eq!(negotiate_encoding("deflate;q=0.5, gzip;q=0.5", &SUPPORTED), Some(GZIP_CONTENT_ENCODING));
+        assert_eq!(negotiate_encoding("*", &SUPPORTED), Some(BR_CONTENT_ENCODING));
+        assert_eq!(negotiate_encoding("br;q=0.4, DEFLATE;q=0.9", &SUPPORTED), Some(DEFLATE_CONTENT_ENCODING));
+    }
+}
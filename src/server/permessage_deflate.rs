@@ -0,0 +1,833 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use bytes::{Buf, BufMut, BytesMut};
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+use http::{header, HeaderMap};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_util::io::poll_read_buf;
+
+const EXTENSION: &str = "permessage-deflate";
+
+// a sync flush ends the compressed data of every message with an empty stored block, left off on the wire
+const DEFLATE_TAIL: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+// the deflate backend always compresses with the largest window
+const MAX_WINDOW_BITS: u8 = 15;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const FIN: u8 = 0x80;
+const RSV1: u8 = 0x40;
+const RSV: u8 = 0x70;
+
+const READ_CHUNK_SIZE: usize = 8 * 1024;
+const INFLATE_CHUNK_SIZE: usize = 16 * 1024;
+// outgoing bytes buffered before writes wait for the peer to read
+const MAX_BUFFERED_WRITES: usize = 128 * 1024;
+
+/// Parameters of an accepted `permessage-deflate` offer (RFC 7692, section 7.1).
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct DeflateParams {
+    server_no_context_takeover: bool,
+    client_no_context_takeover: bool,
+    server_max_window_bits: bool,
+}
+
+impl DeflateParams {
+    /// The first offer of the `Sec-WebSocket-Extensions` headers the server can accept. Offers limiting the server
+    /// window below 15 bits are declined, as are offers with unknown or repeated parameters.
+    pub(crate) fn negotiate(headers: &HeaderMap) -> Option<DeflateParams> {
+        headers
+            .get_all(header::SEC_WEBSOCKET_EXTENSIONS)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .find_map(parse_offer)
+    }
+
+    /// Value of the `Sec-WebSocket-Extensions` response header accepting the offer.
+    pub(crate) fn to_header(&self) -> String {
+        let mut value = EXTENSION.to_string();
+
+        if self.server_no_context_takeover {
+            value.push_str("; server_no_context_takeover");
+        }
+        if self.client_no_context_takeover {
+            value.push_str("; client_no_context_takeover");
+        }
+        if self.server_max_window_bits {
+            value.push_str(&format!("; server_max_window_bits={}", MAX_WINDOW_BITS));
+        }
+
+        value
+    }
+}
+
+fn parse_offer(offer: &str) -> Option<DeflateParams> {
+    let mut params = offer.split(';').map(str::trim);
+    if !params.next()?.eq_ignore_ascii_case(EXTENSION) {
+        return None;
+    }
+
+    let mut accepted = DeflateParams::default();
+    let mut seen = Vec::new();
+    for param in params {
+        let (name, value) = match param.split_once('=') {
+            Some((name, value)) => (name.trim().to_ascii_lowercase(), Some(value.trim().trim_matches('"'))),
+            None => (param.to_ascii_lowercase(), None),
+        };
+
+        if seen.contains(&name) {
+            return None;
+        }
+
+        match (name.as_str(), value) {
+            ("server_no_context_takeover", None) => accepted.server_no_context_takeover = true,
+            ("client_no_context_takeover", None) => accepted.client_no_context_takeover = true,
+            ("server_max_window_bits", Some(bits)) => {
+                if parse_window_bits(bits)? != MAX_WINDOW_BITS {
+                    return None;
+                }
+                accepted.server_max_window_bits = true;
+            }
+            // the client compresses with a smaller window when it likes, the inflater window fits any of them
+            ("client_max_window_bits", None) => {}
+            ("client_max_window_bits", Some(bits)) => {
+                parse_window_bits(bits)?;
+            }
+            _ => return None,
+        }
+
+        seen.push(name);
+    }
+
+    Some(accepted)
+}
+
+fn parse_window_bits(bits: &str) -> Option<u8> {
+    if !bits.bytes().all(|digit| digit.is_ascii_digit()) {
+        return None;
+    }
+
+    bits.parse().ok().filter(|bits| (8..=MAX_WINDOW_BITS).contains(bits))
+}
+
+/// The upgraded connection as tungstenite sees it, with `permessage-deflate` applied when negotiated: compressed
+/// messages are inflated into plain frames as they are read, and the data frames tungstenite writes are deflated.
+///
+/// Inflated messages over `max_message_size` end the stream with an `InvalidData` I/O error.
+pub struct DeflateStream<S> {
+    inner: S,
+    deflate: Option<Box<Deflate>>,
+}
+
+impl<S> DeflateStream<S> {
+    pub(crate) fn new(inner: S, params: Option<DeflateParams>, max_message_size: Option<usize>, max_frame_size: Option<usize>) -> DeflateStream<S> {
+        DeflateStream {
+            inner,
+            deflate: params.map(|params| Box::new(Deflate::new(params, max_message_size, max_frame_size))),
+        }
+    }
+}
+
+struct Deflate {
+    params: DeflateParams,
+    max_message_size: Option<usize>,
+    max_frame_size: Option<usize>,
+
+    read_in: BytesMut,
+    read_out: BytesMut,
+    read_state: ReadState,
+    // opcode and inflated data of the compressed message being read
+    inflating: Option<(u8, Vec<u8>)>,
+    decompress: Decompress,
+    inflate_ended: bool,
+
+    write_in: BytesMut,
+    write_out: BytesMut,
+    write_state: WriteState,
+    deflating: bool,
+    // opcode of the message being deflated, until its first fragment is sent
+    deflating_opcode: Option<u8>,
+    compress: Compress,
+}
+
+enum ReadState {
+    Header,
+    Passthrough {
+        remaining: u64,
+    },
+    Inflate {
+        remaining: u64,
+        mask: [u8; 4],
+        offset: usize,
+        fin: bool,
+    },
+}
+
+enum WriteState {
+    Header,
+    Passthrough {
+        remaining: u64,
+    },
+    Deflate {
+        remaining: u64,
+        fin: bool,
+    },
+}
+
+struct FrameHeader {
+    first_byte: u8,
+    mask: Option<[u8; 4]>,
+    payload_len: u64,
+    header_len: usize,
+}
+
+impl FrameHeader {
+    fn parse(buf: &[u8]) -> Option<FrameHeader> {
+        let (&first_byte, &second_byte) = (buf.first()?, buf.get(1)?);
+
+        let (payload_len, mut header_len) = match second_byte & 0x7f {
+            126 => (u16::from_be_bytes(buf.get(2..4)?.try_into().ok()?) as u64, 4),
+            127 => (u64::from_be_bytes(buf.get(2..10)?.try_into().ok()?), 10),
+            len => (len as u64, 2),
+        };
+
+        let mask = if second_byte & 0x80 != 0 {
+            let mask = buf.get(header_len..header_len + 4)?.try_into().ok()?;
+            header_len += 4;
+            Some(mask)
+        } else {
+            None
+        };
+
+        Some(FrameHeader {
+            first_byte,
+            mask,
+            payload_len,
+            header_len,
+        })
+    }
+
+    fn opcode(&self) -> u8 {
+        self.first_byte & 0x0f
+    }
+
+    fn is_data(&self) -> bool {
+        self.opcode() < 0x8
+    }
+
+    fn is_fin(&self) -> bool {
+        self.first_byte & FIN != 0
+    }
+
+    fn put(out: &mut BytesMut, first_byte: u8, mask: Option<[u8; 4]>, payload_len: usize) {
+        let mask_bit = if mask.is_some() { 0x80 } else { 0 };
+
+        out.put_u8(first_byte);
+        match payload_len {
+            0..=125 => out.put_u8(mask_bit | payload_len as u8),
+            126..=0xffff => {
+                out.put_u8(mask_bit | 126);
+                out.put_u16(payload_len as u16);
+            }
+            _ => {
+                out.put_u8(mask_bit | 127);
+                out.put_u64(payload_len as u64);
+            }
+        }
+        if let Some(mask) = mask {
+            out.put_slice(&mask);
+        }
+    }
+}
+
+impl Deflate {
+    fn new(params: DeflateParams, max_message_size: Option<usize>, max_frame_size: Option<usize>) -> Deflate {
+        Deflate {
+            params,
+            max_message_size,
+            max_frame_size,
+            read_in: BytesMut::new(),
+            read_out: BytesMut::new(),
+            read_state: ReadState::Header,
+            inflating: None,
+            decompress: Decompress::new(false),
+            inflate_ended: false,
+            write_in: BytesMut::new(),
+            write_out: BytesMut::new(),
+            write_state: WriteState::Header,
+            deflating: false,
+            deflating_opcode: None,
+            compress: Compress::new(Compression::default(), false),
+        }
+    }
+
+    /// Moves what it can from `read_in` to `read_out`, telling whether it made progress.
+    fn read_frames(&mut self) -> io::Result<bool> {
+        match &mut self.read_state {
+            ReadState::Header => {
+                let Some(header) = FrameHeader::parse(&self.read_in) else {
+                    return Ok(false);
+                };
+
+                let compressed_start = header.is_data() && header.opcode() != OPCODE_CONTINUATION && header.first_byte & RSV == RSV1;
+                let compressed_continuation = header.is_data()
+                    && header.opcode() == OPCODE_CONTINUATION
+                    && header.first_byte & RSV == 0
+                    && self.inflating.is_some();
+
+                if header.is_data() && header.opcode() != OPCODE_CONTINUATION && self.inflating.is_some() {
+                    return Err(invalid_data("Expected a continuation frame of the compressed message"));
+                }
+
+                if !compressed_start && !compressed_continuation {
+                    // control frames, uncompressed messages, and anything tungstenite should reject on its own
+                    self.read_out.extend_from_slice(&self.read_in.split_to(header.header_len));
+                    self.read_state = ReadState::Passthrough {
+                        remaining: header.payload_len,
+                    };
+                    return Ok(true);
+                }
+
+                if self.max_frame_size.is_some_and(|max_frame_size| header.payload_len > max_frame_size as u64) {
+                    return Err(invalid_data("WebSocket frame exceeds max_frame_size"));
+                }
+
+                if compressed_start {
+                    self.inflating = Some((header.opcode(), Vec::new()));
+                }
+                self.read_in.advance(header.header_len);
+                self.read_state = ReadState::Inflate {
+                    remaining: header.payload_len,
+                    mask: header.mask.unwrap_or_default(),
+                    offset: 0,
+                    fin: header.is_fin(),
+                };
+
+                Ok(true)
+            }
+            ReadState::Passthrough { remaining } => {
+                let len = (*remaining).min(self.read_in.len() as u64) as usize;
+                if len == 0 && *remaining > 0 {
+                    return Ok(false);
+                }
+
+                self.read_out.extend_from_slice(&self.read_in.split_to(len));
+                *remaining -= len as u64;
+                if *remaining == 0 {
+                    self.read_state = ReadState::Header;
+                }
+
+                Ok(true)
+            }
+            ReadState::Inflate { remaining, mask, offset, fin } => {
+                let len = (*remaining).min(self.read_in.len() as u64) as usize;
+                if len == 0 && *remaining > 0 {
+                    return Ok(false);
+                }
+
+                let mut payload = self.read_in.split_to(len);
+                for (index, byte) in payload.iter_mut().enumerate() {
+                    *byte ^= mask[(*offset + index) % 4];
+                }
+                *offset += len;
+                *remaining -= len as u64;
+                let done = (*remaining == 0).then_some(*fin);
+
+                self.inflate(&payload)?;
+
+                match done {
+                    Some(true) => {
+                        self.inflate(&DEFLATE_TAIL)?;
+                        self.finish_message();
+                        self.read_state = ReadState::Header;
+                    }
+                    Some(false) => self.read_state = ReadState::Header,
+                    None => {}
+                }
+
+                Ok(true)
+            }
+        }
+    }
+
+    fn inflate(&mut self, mut input: &[u8]) -> io::Result<()> {
+        let Some((_, data)) = &mut self.inflating else {
+            return Ok(());
+        };
+
+        // data after the final deflate block has no meaning
+        while !self.inflate_ended {
+            if data.capacity() - data.len() < INFLATE_CHUNK_SIZE {
+                data.reserve(INFLATE_CHUNK_SIZE);
+            }
+
+            let (total_in, total_out) = (self.decompress.total_in(), self.decompress.total_out());
+            let status = self
+                .decompress
+                .decompress_vec(input, data, FlushDecompress::Sync)
+                .map_err(|err| invalid_data(&format!("Error in inflating WebSocket message: {}", err)))?;
+            let consumed = (self.decompress.total_in() - total_in) as usize;
+            let produced = (self.decompress.total_out() - total_out) as usize;
+            input = &input[consumed..];
+
+            if self.max_message_size.is_some_and(|max_message_size| data.len() > max_message_size) {
+                return Err(invalid_data("WebSocket message exceeds max_message_size once inflated"));
+            }
+
+            if status == Status::StreamEnd {
+                self.inflate_ended = true;
+            } else if input.is_empty() && data.len() < data.capacity() {
+                // all input taken and room to spare, nothing is held back
+                break;
+            } else if consumed == 0 && produced == 0 {
+                return Err(invalid_data("Error in inflating WebSocket message: truncated data"));
+            }
+        }
+
+        Ok(())
+    }
+
+    // hands the inflated message to tungstenite in frames of at most `max_frame_size`, which tungstenite checks again,
+    // masked with a zero key as client frames must be
+    fn finish_message(&mut self) {
+        let Some((mut opcode, data)) = self.inflating.take() else {
+            return;
+        };
+
+        let frame_size = self.max_frame_size.unwrap_or(usize::MAX).max(1);
+        let mut rest = &data[..];
+        loop {
+            let len = rest.len().min(frame_size);
+            let fin = if len == rest.len() { FIN } else { 0 };
+
+            FrameHeader::put(&mut self.read_out, fin | opcode, Some([0; 4]), len);
+            self.read_out.extend_from_slice(&rest[..len]);
+            rest = &rest[len..];
+
+            if fin == FIN {
+                break;
+            }
+            opcode = OPCODE_CONTINUATION;
+        }
+
+        // a final deflate block ends the client's compression context as well
+        if self.params.client_no_context_takeover || self.inflate_ended {
+            self.decompress.reset(false);
+            self.inflate_ended = false;
+        }
+    }
+
+    /// Moves `write_in` to `write_out` as its bytes arrive, deflating data frames. The compressed bytes of a frame are
+    /// sent as fragments of the message, so that a large frame is never held whole.
+    fn write_frames(&mut self) -> io::Result<()> {
+        loop {
+            match &mut self.write_state {
+                WriteState::Header => {
+                    let Some(header) = FrameHeader::parse(&self.write_in) else {
+                        return Ok(());
+                    };
+
+                    if !header.is_data() || header.mask.is_some() {
+                        self.write_out.extend_from_slice(&self.write_in.split_to(header.header_len));
+                        self.write_state = WriteState::Passthrough {
+                            remaining: header.payload_len,
+                        };
+                        continue;
+                    }
+
+                    if header.opcode() != OPCODE_CONTINUATION || !self.deflating {
+                        self.deflating = true;
+                        self.deflating_opcode = Some(header.opcode());
+                    }
+                    self.write_in.advance(header.header_len);
+                    self.write_state = WriteState::Deflate {
+                        remaining: header.payload_len,
+                        fin: header.is_fin(),
+                    };
+                }
+                WriteState::Passthrough { remaining } => {
+                    let len = (*remaining).min(self.write_in.len() as u64) as usize;
+                    if len == 0 && *remaining > 0 {
+                        return Ok(());
+                    }
+
+                    self.write_out.extend_from_slice(&self.write_in.split_to(len));
+                    *remaining -= len as u64;
+                    if *remaining == 0 {
+                        self.write_state = WriteState::Header;
+                    }
+                }
+                WriteState::Deflate { remaining, fin } => {
+                    let len = (*remaining).min(self.write_in.len() as u64) as usize;
+                    if len == 0 && *remaining > 0 {
+                        return Ok(());
+                    }
+
+                    *remaining -= len as u64;
+                    let frame_end = *remaining == 0;
+                    let message_end = frame_end && *fin;
+                    if frame_end {
+                        self.write_state = WriteState::Header;
+                    }
+
+                    let input = self.write_in.split_to(len);
+                    let mut payload = Vec::with_capacity(len / 2 + 64);
+                    let flush = if message_end { FlushCompress::Sync } else { FlushCompress::None };
+                    self.deflate(&input, flush, &mut payload)?;
+
+                    if message_end {
+                        payload.truncate(payload.len().saturating_sub(DEFLATE_TAIL.len()));
+                        if self.params.server_no_context_takeover {
+                            self.compress.reset();
+                        }
+                    } else if payload.is_empty() {
+                        // the deflater holds the input back for now
+                        continue;
+                    }
+
+                    let first_byte = match self.deflating_opcode.take() {
+                        Some(opcode) => opcode | RSV1,
+                        None => OPCODE_CONTINUATION,
+                    };
+                    let first_byte = if message_end { FIN | first_byte } else { first_byte };
+                    FrameHeader::put(&mut self.write_out, first_byte, None, payload.len());
+                    self.write_out.extend_from_slice(&payload);
+
+                    if message_end {
+                        self.deflating = false;
+                    }
+                }
+            }
+        }
+    }
+
+    fn deflate(&mut self, mut input: &[u8], flush: FlushCompress, output: &mut Vec<u8>) -> io::Result<()> {
+        loop {
+            if output.capacity() - output.len() < 64 {
+                output.reserve(input.len() / 2 + 64);
+            }
+
+            let total_in = self.compress.total_in();
+            self.compress
+                .compress_vec(input, output, flush)
+                .map_err(|err| io::Error::other(format!("Error in deflating WebSocket message: {}", err)))?;
+            input = &input[(self.compress.total_in() - total_in) as usize..];
+
+            // spare room left once all input is taken means the flush completed
+            if input.is_empty() && output.len() < output.capacity() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn poll_write_all<S>(inner: &mut S, buf: &mut BytesMut, cx: &mut Context<'_>) -> Poll<io::Result<()>>
+    where
+        S: AsyncWrite + Unpin,
+{
+    while !buf.is_empty() {
+        let written = ready!(Pin::new(&mut *inner).poll_write(cx, buf))?;
+        if written == 0 {
+            return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+        }
+        buf.advance(written);
+    }
+
+    Poll::Ready(Ok(()))
+}
+
+impl<S> AsyncRead for DeflateStream<S>
+    where
+        S: AsyncRead + Unpin,
+{
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let Some(deflate) = &mut this.deflate else {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        };
+
+        loop {
+            if !deflate.read_out.is_empty() {
+                let len = buf.remaining().min(deflate.read_out.len());
+                buf.put_slice(&deflate.read_out.split_to(len));
+                return Poll::Ready(Ok(()));
+            }
+
+            if deflate.read_frames()? {
+                continue;
+            }
+
+            deflate.read_in.reserve(READ_CHUNK_SIZE);
+            if ready!(poll_read_buf(Pin::new(&mut this.inner), cx, &mut deflate.read_in))? == 0 {
+                // end of stream, tungstenite tells a clean close from a reset
+                return Poll::Ready(Ok(()));
+            }
+        }
+    }
+}
+
+impl<S> AsyncWrite for DeflateStream<S>
+    where
+        S: AsyncWrite + Unpin,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let Some(deflate) = &mut this.deflate else {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        };
+
+        if deflate.write_out.len() >= MAX_BUFFERED_WRITES {
+            ready!(poll_write_all(&mut this.inner, &mut deflate.write_out, cx))?;
+        }
+
+        // frames are deflated as their bytes arrive, only a partial frame header is ever left in `write_in`
+        let buf = &buf[..buf.len().min(MAX_BUFFERED_WRITES)];
+        deflate.write_in.extend_from_slice(buf);
+        deflate.write_frames()?;
+
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if let Some(deflate) = &mut this.deflate {
+            ready!(poll_write_all(&mut this.inner, &mut deflate.write_out, cx))?;
+        }
+
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if let Some(deflate) = &mut this.deflate {
+            ready!(poll_write_all(&mut this.inner, &mut deflate.write_out, cx))?;
+        }
+
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::HeaderValue;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    fn negotiate(offers: &str) -> Option<String> {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::SEC_WEBSOCKET_EXTENSIONS, HeaderValue::from_str(offers).unwrap());
+
+        DeflateParams::negotiate(&headers).map(|params| params.to_header())
+    }
+
+    #[test]
+    fn accepts_the_first_supported_offer() {
+        assert_eq!(negotiate("permessage-deflate; client_max_window_bits").as_deref(), Some("permessage-deflate"));
+        assert_eq!(
+            negotiate("permessage-deflate; server_max_window_bits=10, permessage-deflate; server_no_context_takeover").as_deref(),
+            Some("permessage-deflate; server_no_context_takeover")
+        );
+        assert_eq!(
+            negotiate("x-webkit-deflate-frame, permessage-deflate; client_no_context_takeover; server_max_window_bits=\"15\"").as_deref(),
+            Some("permessage-deflate; client_no_context_takeover; server_max_window_bits=15")
+        );
+    }
+
+    #[test]
+    fn declines_invalid_offers() {
+        assert_eq!(negotiate("permessage-deflate; server_no_context_takeover; server_no_context_takeover"), None);
+        assert_eq!(negotiate("permessage-deflate; client_max_window_bits=16"), None);
+        assert_eq!(negotiate("permessage-deflate; client_max_window_bits=+9"), None);
+        assert_eq!(negotiate("permessage-deflate; server_max_window_bits"), None);
+        assert_eq!(negotiate("permessage-deflate; unknown"), None);
+        assert_eq!(negotiate("x-webkit-deflate-frame"), None);
+    }
+
+    fn client_frame(first_byte: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let mut frame = BytesMut::new();
+        FrameHeader::put(&mut frame, first_byte, Some(mask), payload.len());
+        frame.extend(payload.iter().enumerate().map(|(index, byte)| byte ^ mask[index % 4]));
+
+        frame.to_vec()
+    }
+
+    fn client_deflate(compress: &mut Compress, data: &[u8]) -> Vec<u8> {
+        let mut output = Vec::with_capacity(data.len() + 64);
+        compress.compress_vec(data, &mut output, FlushCompress::Sync).unwrap();
+        output.truncate(output.len() - DEFLATE_TAIL.len());
+
+        output
+    }
+
+    #[tokio::test]
+    async fn inflates_compressed_messages() {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let mut stream = DeflateStream::new(server, Some(DeflateParams::default()), Some(1024), None);
+        let (_client_read, mut client_write) = tokio::io::split(client);
+
+        let mut compress = Compress::new(Compression::default(), false);
+        let compressed = client_deflate(&mut compress, b"hello hello hello");
+        let mut frames = client_frame(0x01 | RSV1, &compressed[..4]);
+        frames.extend(client_frame(0x89, b"ping"));
+        frames.extend(client_frame(FIN, &compressed[4..]));
+        frames.extend(client_frame(0x82, b"plain"));
+        client_write.write_all(&frames).await.unwrap();
+
+        for (first_byte, payload) in [(0x89, &b"ping"[..]), (0x81, b"hello hello hello"), (0x82, b"plain")] {
+            let frame = client_frame(first_byte, payload);
+            let header = FrameHeader::parse(&frame).unwrap();
+            let mut read = vec![0; header.header_len + payload.len()];
+            stream.read_exact(&mut read).await.unwrap();
+
+            let read_header = FrameHeader::parse(&read).unwrap();
+            let unmasked: Vec<_> = read[read_header.header_len..]
+                .iter()
+                .enumerate()
+                .map(|(index, byte)| byte ^ read_header.mask.unwrap()[index % 4])
+                .collect();
+            assert_eq!(read_header.first_byte, first_byte);
+            assert_eq!(unmasked, payload);
+        }
+
+        // over max_message_size once inflated
+        let compressed = client_deflate(&mut compress, &[0; 2048]);
+        client_write.write_all(&client_frame(0xc2, &compressed)).await.unwrap();
+        let err = stream.read(&mut [0; 16]).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn splits_inflated_messages_over_max_frame_size() {
+        use futures::StreamExt;
+        use tokio_tungstenite::tungstenite::protocol::{Role, WebSocketConfig};
+        use tokio_tungstenite::tungstenite::Message;
+        use tokio_tungstenite::WebSocketStream;
+
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let stream = DeflateStream::new(server, Some(DeflateParams::default()), Some(64 * 1024), Some(1024));
+        let config = WebSocketConfig::default().max_message_size(Some(64 * 1024)).max_frame_size(Some(1024));
+        let mut socket = WebSocketStream::from_raw_socket(stream, Role::Server, Some(config)).await;
+        let (_client_read, mut client_write) = tokio::io::split(client);
+
+        // far over max_frame_size once inflated, but a single small frame on the wire
+        let message = "hello ".repeat(1000);
+        let mut compress = Compress::new(Compression::default(), false);
+        let compressed = client_deflate(&mut compress, message.as_bytes());
+        assert!(compressed.len() < 1024);
+        client_write.write_all(&client_frame(FIN | RSV1 | 0x01, &compressed)).await.unwrap();
+
+        let received = socket.next().await.unwrap().unwrap();
+        assert_eq!(received, Message::text(message));
+    }
+
+    #[tokio::test]
+    async fn deflates_data_frames() {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let mut stream = DeflateStream::new(server, Some(DeflateParams::default()), None, None);
+        let (mut client_read, _client_write) = tokio::io::split(client);
+
+        let mut frames = BytesMut::new();
+        for payload in [&b"hello hello hello"[..], b"hello hello hello", b""] {
+            FrameHeader::put(&mut frames, 0x81, None, payload.len());
+            frames.extend_from_slice(payload);
+        }
+        FrameHeader::put(&mut frames, 0x8a, None, 4);
+        frames.extend_from_slice(b"pong");
+        stream.write_all(&frames).await.unwrap();
+        stream.flush().await.unwrap();
+
+        let mut decompress = Decompress::new(false);
+        let mut messages = Vec::new();
+        for _ in 0..4 {
+            let mut header = [0; 2];
+            client_read.read_exact(&mut header).await.unwrap();
+            let mut payload = vec![0; (header[1] & 0x7f) as usize];
+            client_read.read_exact(&mut payload).await.unwrap();
+
+            if header[0] & RSV1 != 0 {
+                payload.extend_from_slice(&DEFLATE_TAIL);
+                let mut inflated = Vec::with_capacity(1024);
+                decompress.decompress_vec(&payload, &mut inflated, FlushDecompress::Sync).unwrap();
+                payload = inflated;
+            }
+            messages.push((header[0], payload));
+        }
+
+        assert_eq!(
+            messages,
+            [
+                (0xc1, b"hello hello hello".to_vec()),
+                (0xc1, b"hello hello hello".to_vec()),
+                (0xc1, Vec::new()),
+                (0x8a, b"pong".to_vec()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn deflates_large_frames_as_fragments() {
+        let (client, server) = tokio::io::duplex(1024 * 1024);
+        let mut stream = DeflateStream::new(server, Some(DeflateParams::default()), None, None);
+        let (mut client_read, _client_write) = tokio::io::split(client);
+
+        // hardly compressible, so that the deflater gives output before the frame ends
+        let mut state = 0x2545_f491_u32;
+        let message: Vec<u8> = (0..512 * 1024)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect();
+        let mut frame = BytesMut::new();
+        FrameHeader::put(&mut frame, 0x82, None, message.len());
+        frame.extend_from_slice(&message);
+
+        let mut written = 0;
+        while written < frame.len() {
+            written += stream.write(&frame[written..]).await.unwrap();
+            assert!(stream.deflate.as_ref().unwrap().write_in.len() < 14);
+        }
+        stream.flush().await.unwrap();
+
+        let mut decompress = Decompress::new(false);
+        let mut first_bytes = Vec::new();
+        let mut inflated = Vec::with_capacity(message.len() + 1024);
+        loop {
+            let mut header = [0; 2];
+            client_read.read_exact(&mut header).await.unwrap();
+            let payload_len = match header[1] & 0x7f {
+                126 => client_read.read_u16().await.unwrap() as usize,
+                127 => client_read.read_u64().await.unwrap() as usize,
+                len => len as usize,
+            };
+            let mut payload = vec![0; payload_len];
+            client_read.read_exact(&mut payload).await.unwrap();
+            first_bytes.push(header[0]);
+
+            if header[0] & FIN != 0 {
+                payload.extend_from_slice(&DEFLATE_TAIL);
+            }
+            decompress.decompress_vec(&payload, &mut inflated, FlushDecompress::Sync).unwrap();
+            if header[0] & FIN != 0 {
+                break;
+            }
+        }
+
+        assert!(first_bytes.len() > 1);
+        assert_eq!(first_bytes[0], 0x42);
+        assert!(first_bytes[1..first_bytes.len() - 1].iter().all(|&first_byte| first_byte == 0x00));
+        assert_eq!(first_bytes[first_bytes.len() - 1], 0x80);
+        assert_eq!(inflated, message);
+    }
+}
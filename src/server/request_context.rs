@@ -0,0 +1,81 @@
+use std::fmt::Write;
+use std::sync::Arc;
+
+use http::{HeaderMap, Request};
+use hyper::Body;
+
+use super::ServerConfig;
+
+pub(crate) const REQUEST_ID_HEADER: &str = "x-request-id";
+const TRACEPARENT_HEADER: &str = "traceparent";
+
+// longer ids are more likely garbage than a correlation id worth keeping
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+/// What the tasks serving a request know about it, error responses and log records included.
+pub(crate) struct RequestContext {
+    pub(crate) path: String,
+    pub(crate) request_id: Arc<str>,
+    pub(crate) expose_error_details: bool,
+}
+
+tokio::task_local! {
+    pub(crate) static REQUEST_CONTEXT: RequestContext;
+}
+
+impl RequestContext {
+    pub(crate) fn new(req: &Request<Body>, config: &ServerConfig) -> RequestContext {
+        RequestContext {
+            path: req.uri().path().to_string(),
+            request_id: request_id(req.headers()),
+            expose_error_details: config.expose_error_details,
+        }
+    }
+}
+
+/// Id of the request served by the current task, which `setup_logging` prefixes log records with. `None` outside of
+/// request handling, including tasks spawned by handlers.
+pub fn current_request_id() -> Option<Arc<str>> {
+    REQUEST_CONTEXT.try_with(|context| context.request_id.clone()).ok()
+}
+
+/// The client's `X-Request-Id`, else the trace id of its W3C `traceparent`, else a new UUID v4.
+pub(crate) fn request_id(headers: &HeaderMap) -> Arc<str> {
+    headers
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| is_valid_request_id(value))
+        .or_else(|| headers.get(TRACEPARENT_HEADER).and_then(|value| value.to_str().ok()).and_then(trace_id))
+        .map(Arc::from)
+        .unwrap_or_else(|| Arc::from(uuid_v4()))
+}
+
+fn is_valid_request_id(value: &str) -> bool {
+    !value.is_empty() && value.len() <= MAX_REQUEST_ID_LENGTH && value.bytes().all(|byte| byte.is_ascii_graphic())
+}
+
+// `00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01`, an all zero trace id being invalid
+fn trace_id(traceparent: &str) -> Option<&str> {
+    let mut fields = traceparent.trim().split('-');
+    let _version = fields.next()?;
+    let trace_id = fields.next()?;
+
+    let is_hex = trace_id.bytes().all(|byte| matches!(byte, b'0'..=b'9' | b'a'..=b'f'));
+    (trace_id.len() == 32 && is_hex && trace_id.bytes().any(|byte| byte != b'0')).then_some(trace_id)
+}
+
+fn uuid_v4() -> String {
+    let mut bytes: [u8; 16] = rand::random();
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+
+    let mut uuid = String::with_capacity(36);
+    for (index, byte) in bytes.iter().enumerate() {
+        if matches!(index, 4 | 6 | 8 | 10) {
+            uuid.push('-');
+        }
+        let _ = write!(uuid, "{:02x}", byte);
+    }
+
+    uuid
+}
@@ -0,0 +1,430 @@
+use std::borrow::Cow;
+use std::fmt::Display;
+use std::str::FromStr;
+
+use anyhow::Context;
+use async_trait::async_trait;
+use futures::future::BoxFuture;
+use http::{header, HeaderValue, Method, Response};
+use hyper::Body;
+
+use super::{ApiError, ApiResult, HttpResponse, HttpResult, HttpRoute, Middleware, Service, UNMATCHED_METRIC_PATH};
+
+type Handler<S> = Box<dyn for<'a> Fn(&'a S, Body, &'a HttpRoute<'a>, PathParams<'a>) -> BoxFuture<'a, HttpResult> + Send + Sync>;
+
+/// Routes requests by method and path template, e.g. `GET /api/users/:id`, to handlers called with the router's state,
+/// the body, the route and the `PathParams` of the template:
+///
+/// ```ignore
+/// let router = Router::new(app)
+///     .get("/api/users/:id", |app, _body, route, params| Box::pin(app.get_user(route, params)));
+/// ```
+///
+/// Templates are full paths, as the router sees every request under `/api` when it is the `Service` given to
+/// `start_http_server`, and may end with a `*rest` parameter taking the remaining path. Routes are tried in the order
+/// they were registered, and the template of the matching one becomes the request's metric path; paths matching no
+/// route are recorded under `UNMATCHED_METRIC_PATH`.
+pub struct Router<S> {
+    state: S,
+    endpoints: Vec<Endpoint<S>>,
+    middlewares: Vec<Box<dyn Middleware>>,
+}
+
+struct Endpoint<S> {
+    method: Method,
+    template: &'static str,
+    segments: Vec<Segment>,
+    handler: Handler<S>,
+}
+
+enum Segment {
+    Static(&'static str),
+    Param(&'static str),
+    Rest(&'static str),
+}
+
+impl<S> Router<S>
+    where
+        S: Send + Sync + 'static,
+{
+    pub fn new(state: S) -> Router<S> {
+        Router {
+            state,
+            endpoints: Vec::new(),
+            middlewares: Vec::new(),
+        }
+    }
+
+    pub fn state(&self) -> &S {
+        &self.state
+    }
+
+    /// Registers `handler` for `method` requests matching `template`.
+    ///
+    /// # Panics
+    ///
+    /// If `template` does not start with `/`, has an unnamed parameter, or a `*rest` parameter before its end.
+    pub fn route<H>(mut self, method: Method, template: &'static str, handler: H) -> Router<S>
+        where
+            H: for<'a> Fn(&'a S, Body, &'a HttpRoute<'a>, PathParams<'a>) -> BoxFuture<'a, HttpResult> + Send + Sync + 'static,
+    {
+        self.endpoints.push(Endpoint {
+            method,
+            template,
+            segments: parse_template(template),
+            handler: Box::new(handler),
+        });
+        self
+    }
+
+    /// Adds `middleware` after the ones added before, run when the router is the `Service` given to
+    /// `start_http_server`.
+    pub fn middleware<M>(mut self, middleware: M) -> Router<S>
+        where
+            M: Middleware + 'static,
+    {
+        self.middlewares.push(Box::new(middleware));
+        self
+    }
+
+    pub fn get<H>(self, template: &'static str, handler: H) -> Router<S>
+        where
+            H: for<'a> Fn(&'a S, Body, &'a HttpRoute<'a>, PathParams<'a>) -> BoxFuture<'a, HttpResult> + Send + Sync + 'static,
+    {
+        self.route(Method::GET, template, handler)
+    }
+
+    pub fn post<H>(self, template: &'static str, handler: H) -> Router<S>
+        where
+            H: for<'a> Fn(&'a S, Body, &'a HttpRoute<'a>, PathParams<'a>) -> BoxFuture<'a, HttpResult> + Send + Sync + 'static,
+    {
+        self.route(Method::POST, template, handler)
+    }
+
+    pub fn put<H>(self, template: &'static str, handler: H) -> Router<S>
+        where
+            H: for<'a> Fn(&'a S, Body, &'a HttpRoute<'a>, PathParams<'a>) -> BoxFuture<'a, HttpResult> + Send + Sync + 'static,
+    {
+        self.route(Method::PUT, template, handler)
+    }
+
+    pub fn patch<H>(self, template: &'static str, handler: H) -> Router<S>
+        where
+            H: for<'a> Fn(&'a S, Body, &'a HttpRoute<'a>, PathParams<'a>) -> BoxFuture<'a, HttpResult> + Send + Sync + 'static,
+    {
+        self.route(Method::PATCH, template, handler)
+    }
+
+    pub fn delete<H>(self, template: &'static str, handler: H) -> Router<S>
+        where
+            H: for<'a> Fn(&'a S, Body, &'a HttpRoute<'a>, PathParams<'a>) -> BoxFuture<'a, HttpResult> + Send + Sync + 'static,
+    {
+        self.route(Method::DELETE, template, handler)
+    }
+
+    /// Calls the handler of the first route matching the request. A path registered for other methods only gets
+    /// `405 Method Not Allowed`, unless the request is a `HEAD`, served by the `GET` handler without its body, or an
+    /// `OPTIONS`, answered with the allowed methods. Other paths get `404 Not Found`.
+    pub async fn handle(&self, body: Body, route: &HttpRoute<'_>) -> HttpResult {
+        let path_segments = path_segments(route.path);
+
+        let mut matched = None;
+        let mut get_endpoint = None;
+        let mut allow = Vec::new();
+        for endpoint in &self.endpoints {
+            let Some(params) = endpoint.matches(route.path, &path_segments) else {
+                continue;
+            };
+
+            if endpoint.method == route.method {
+                route.set_metric_path(endpoint.template);
+                return (endpoint.handler)(&self.state, body, route, params).await;
+            }
+
+            matched.get_or_insert(endpoint.template);
+            if endpoint.method == Method::GET && get_endpoint.is_none() {
+                get_endpoint = Some((endpoint, params));
+            }
+            if !allow.contains(&endpoint.method) {
+                allow.push(endpoint.method.clone());
+            }
+        }
+
+        let Some(template) = matched else {
+            route.set_metric_path(UNMATCHED_METRIC_PATH);
+            return HttpResponse::not_found(route.path);
+        };
+        route.set_metric_path(template);
+
+        if let (&Method::HEAD, Some((endpoint, params))) = (route.method, get_endpoint) {
+            let response = (endpoint.handler)(&self.state, body, route, params).await?;
+            return Ok(strip_body(response));
+        }
+
+        if allow.contains(&Method::GET) {
+            allow.push(Method::HEAD);
+        }
+        allow.push(Method::OPTIONS);
+
+        match *route.method {
+            Method::OPTIONS => HttpResponse::options(&allow),
+            _ => Err(ApiError::MethodNotAllowed(allow)),
+        }
+    }
+}
+
+// the body of a HEAD response is never sent, its length is still announced when known
+fn strip_body(response: Response<Body>) -> Response<Body> {
+    let (mut parts, body) = response.into_parts();
+
+    if !parts.headers.contains_key(header::CONTENT_LENGTH) {
+        if let Some(length) = hyper::body::HttpBody::size_hint(&body).exact() {
+            parts.headers.insert(header::CONTENT_LENGTH, HeaderValue::from(length));
+        }
+    }
+
+    Response::from_parts(parts, Body::empty())
+}
+
+#[async_trait]
+impl<S> Service for Router<S>
+    where
+        S: Send + Sync + 'static,
+{
+    async fn api_handler<'a>(
+        &'a self,
+        body: Body,
+        route: &HttpRoute<'a>,
+        _path: &[&str],
+    ) -> HttpResult {
+        self.handle(body, route).await
+    }
+
+    fn middlewares(&self) -> &[Box<dyn Middleware>] {
+        &self.middlewares
+    }
+}
+
+impl<S> Endpoint<S> {
+    fn matches<'r>(&self, path: &'r str, path_segments: &[(usize, &'r str)]) -> Option<PathParams<'r>> {
+        let mut params = Vec::new();
+
+        for (index, segment) in self.segments.iter().enumerate() {
+            match segment {
+                Segment::Static(name) => {
+                    if path_segments.get(index)?.1 != *name {
+                        return None;
+                    }
+                }
+                Segment::Param(name) => params.push((*name, path_segments.get(index)?.1)),
+                Segment::Rest(name) => {
+                    let rest = path_segments
+                        .get(index)
+                        .map(|(offset, _)| path[*offset..].trim_end_matches('/'))
+                        .unwrap_or_default();
+                    params.push((*name, rest));
+
+                    return Some(PathParams { params });
+                }
+            }
+        }
+
+        (path_segments.len() == self.segments.len()).then_some(PathParams { params })
+    }
+}
+
+fn parse_template(template: &'static str) -> Vec<Segment> {
+    assert!(template.starts_with('/'), "Route template must start with '/': {}", template);
+
+    let segments: Vec<_> = template
+        .split('/')
+        .filter(|part| !part.is_empty())
+        .map(|part| {
+            if let Some(name) = part.strip_prefix(':') {
+                assert!(!name.is_empty(), "Unnamed parameter in route template: {}", template);
+                Segment::Param(name)
+            } else if let Some(name) = part.strip_prefix('*') {
+                assert!(!name.is_empty(), "Unnamed parameter in route template: {}", template);
+                Segment::Rest(name)
+            } else {
+                Segment::Static(part)
+            }
+        })
+        .collect();
+
+    let rest_position = segments.iter().position(|segment| matches!(segment, Segment::Rest(_)));
+    assert!(
+        rest_position.is_none_or(|position| position == segments.len() - 1),
+        "Rest parameter must end the route template: {}",
+        template
+    );
+
+    segments
+}
+
+// non-empty segments with their offset in the path, so that a rest parameter can take the remaining path as it is
+fn path_segments(path: &str) -> Vec<(usize, &str)> {
+    let mut offset = 0;
+    let mut segments = Vec::new();
+
+    for part in path.split('/') {
+        if !part.is_empty() {
+            segments.push((offset, part));
+        }
+        offset += part.len() + 1;
+    }
+
+    segments
+}
+
+/// Parameters captured by a route template, e.g. `id` of `/api/users/:id`.
+#[derive(Clone, Debug, Default)]
+pub struct PathParams<'a> {
+    params: Vec<(&'static str, &'a str)>,
+}
+
+impl<'a> PathParams<'a> {
+    /// Value of parameter `name` as it appears in the path, still percent-encoded.
+    pub fn raw(&self, name: &str) -> Option<&'a str> {
+        self.params
+            .iter()
+            .find(|(param, _)| *param == name)
+            .map(|(_, value)| *value)
+    }
+
+    /// Percent-decodes parameter `name` and parses it, e.g. `params.get::<u64>("id")?`. Missing and malformed
+    /// parameters are a `400 Bad Request`.
+    pub fn get<T>(&self, name: &str) -> ApiResult<T>
+        where
+            T: FromStr,
+            T::Err: Display,
+    {
+        let raw = self
+            .raw(name)
+            .with_context(|| format!("Missing path parameter: {}", name))?;
+        let value = percent_decode(raw)?;
+
+        let value = value
+            .parse()
+            .map_err(|err| anyhow::anyhow!("Invalid path parameter {}: {:?}, {}", name, value, err))?;
+
+        Ok(value)
+    }
+}
+
+fn percent_decode(value: &str) -> anyhow::Result<Cow<'_, str>> {
+    if !value.contains('%') {
+        return Ok(Cow::Borrowed(value));
+    }
+
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        if bytes[index] == b'%' {
+            let byte = bytes
+                .get(index + 1..index + 3)
+                .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+                .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok())
+                .with_context(|| format!("Invalid percent-encoding in path parameter: {:?}", value))?;
+            decoded.push(byte);
+            index += 3;
+        } else {
+            decoded.push(bytes[index]);
+            index += 1;
+        }
+    }
+
+    let decoded = String::from_utf8(decoded).with_context(|| format!("Path parameter is not UTF-8: {:?}", value))?;
+
+    Ok(Cow::Owned(decoded))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use http::Request;
+
+    use super::*;
+
+    fn router(template: &'static str) -> Router<()> {
+        Router::new(()).get(template, |_, _, _, _| Box::pin(async { Err(ApiError::NotFound(String::new())) }))
+    }
+
+    fn matches(template: &'static str, path: &str) -> Option<Vec<(&'static str, String)>> {
+        let router = router(template);
+        let params = router.endpoints[0].matches(path, &path_segments(path))?;
+
+        Some(params.params.iter().map(|(name, value)| (*name, value.to_string())).collect())
+    }
+
+    #[test]
+    fn matches_static_and_param_segments() {
+        assert_eq!(matches("/api/users/:id", "/api/users/42"), Some(vec![("id", "42".to_string())]));
+        assert_eq!(matches("/api/users/:id", "/api/users/42/"), Some(vec![("id", "42".to_string())]));
+        assert_eq!(matches("/api/users/:id", "/api/users"), None);
+        assert_eq!(matches("/api/users/:id", "/api/users/42/orders"), None);
+        assert_eq!(matches("/api/users/:id", "/api/orders/42"), None);
+        assert_eq!(matches("/api/users", "/api/users"), Some(vec![]));
+    }
+
+    #[test]
+    fn matches_rest_of_path() {
+        assert_eq!(matches("/api/files/*path", "/api/files/a/b.txt"), Some(vec![("path", "a/b.txt".to_string())]));
+        assert_eq!(matches("/api/files/*path", "/api/files/a/"), Some(vec![("path", "a".to_string())]));
+        assert_eq!(matches("/api/files/*path", "/api/files"), Some(vec![("path", String::new())]));
+        assert_eq!(matches("/api/files/*path", "/api/files/"), Some(vec![("path", String::new())]));
+        assert_eq!(matches("/api/files/*path", "/api/other/a"), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Rest parameter must end the route template")]
+    fn rejects_rest_before_end_of_template() {
+        router("/api/*path/more");
+    }
+
+    #[test]
+    fn percent_decodes_values() {
+        assert!(matches!(percent_decode("plain"), Ok(Cow::Borrowed("plain"))));
+        assert_eq!(percent_decode("a%20b").unwrap(), "a b");
+        assert_eq!(percent_decode("a%2Fb%2f").unwrap(), "a/b/");
+        assert_eq!(percent_decode("%E2%82%AC").unwrap(), "€");
+        assert_eq!(percent_decode("100%25").unwrap(), "100%");
+    }
+
+    #[test]
+    fn rejects_malformed_percent_encoding() {
+        assert!(percent_decode("%").is_err());
+        assert!(percent_decode("a%2").is_err());
+        assert!(percent_decode("%zz").is_err());
+        assert!(percent_decode("%+1").is_err());
+        assert!(percent_decode("%FF").is_err());
+        assert!(percent_decode("%E2%82").is_err());
+    }
+
+    #[test]
+    fn parses_params() {
+        let params = PathParams {
+            params: vec![("id", "42"), ("name", "a%20b"), ("bad", "4x")],
+        };
+
+        assert_eq!(params.get::<u64>("id").unwrap(), 42);
+        assert_eq!(params.get::<String>("name").unwrap(), "a b");
+        assert_eq!(params.raw("name"), Some("a%20b"));
+        assert!(params.get::<u64>("bad").is_err());
+        assert!(params.get::<u64>("missing").is_err());
+    }
+
+    #[tokio::test]
+    async fn records_unmatched_paths_under_a_constant_metric_path() {
+        let router = router("/api/users/:id");
+        for (path, metric_path) in [("/api/users/42", "/api/users/:id"), ("/api/orders/42", UNMATCHED_METRIC_PATH)] {
+            let req = Request::get(path).body(Body::empty()).unwrap();
+            let route = HttpRoute::new(&req, chrono::Local::now(), Instant::now(), ([127, 0, 0, 1], 0).into());
+            let _ = router.handle(Body::empty(), &route).await;
+
+            assert_eq!(route.metric_path(), metric_path);
+        }
+    }
+}
@@ -5,7 +5,7 @@ use async_trait::async_trait;
 use http::Response;
 use hyper::Body;
 
-use crate::server::{ApiError, HttpRoute};
+use crate::server::{ApiError, HttpRoute, Middleware};
 
 lazy_static! {
     pub static ref IN_ROTATION: AtomicBool = AtomicBool::new(true);
@@ -25,6 +25,11 @@ pub trait Service: Send + Sync {
         route: &HttpRoute<'a>,
         path: &[&str],
     ) -> Result<Response<Body>, ApiError>;
+
+    /// Middlewares run around `api_handler`, in order, the first one seeing the request first and the response last.
+    fn middlewares(&self) -> &[Box<dyn Middleware>] {
+        &[]
+    }
 }
 
 #[async_trait]
@@ -1,7 +1,9 @@
 use anyhow::Context;
-use config::{Config, File, FileFormat};
+use config::{Config, ConfigError, File, FileFormat};
 use parking_lot::RwLock;
 
+use super::config::ServerConfig;
+
 lazy_static! {
     pub static ref SETTINGS: RwLock<Config> = RwLock::new(Config::default());
     pub static ref HTTP_WORKERS: usize = http_workers();
@@ -16,10 +18,30 @@ fn http_workers() -> usize {
     settings().read().get::<usize>("http_workers").unwrap_or_else(|_| 1)
 }
 
-fn json_payload_limit() -> usize {
+pub fn json_payload_limit() -> usize {
     settings().read().get::<usize>("json_payload_limit").unwrap_or_else(|_| 1_048_576)
 }
 
+pub fn server_config() -> anyhow::Result<ServerConfig> {
+    // the read guard is dropped before reading again, as a second read waits behind any writer already waiting
+    let (server_config, max_body_size) = {
+        let settings = settings().read();
+        (settings.get::<ServerConfig>("server"), settings.get::<usize>("server.max_body_size"))
+    };
+
+    let mut server_config = match server_config {
+        Ok(server_config) => server_config,
+        Err(ConfigError::NotFound(_)) => ServerConfig::default(),
+        Err(err) => return Err(err).with_context(|| "Error in reading server config"),
+    };
+
+    if matches!(max_body_size, Err(ConfigError::NotFound(_))) {
+        server_config.max_body_size = json_payload_limit();
+    }
+
+    Ok(server_config)
+}
+
 pub fn load_global_config(base_dir: &str, env: &str) -> anyhow::Result<()> {
     let mut write_guard = settings().write();
 
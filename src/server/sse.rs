@@ -0,0 +1,167 @@
+use std::fmt::Write;
+use std::time::Duration;
+
+use anyhow::Context;
+use bytes::Bytes;
+use futures::{stream, Stream, StreamExt};
+use serde::Serialize;
+use tokio::time::{Instant, MissedTickBehavior};
+
+#[cfg(feature = "metrics")]
+use super::logger::METRICS_LOGGER;
+
+/// A single Server-Sent Event, as sent by `HttpResponse::sse`, e.g. `SseEvent::data("42").id("7").event("price")`.
+#[derive(Clone, Debug, Default)]
+pub struct SseEvent {
+    id: Option<String>,
+    event: Option<String>,
+    data: String,
+    retry: Option<Duration>,
+}
+
+impl SseEvent {
+    /// Event carrying `data`, which may span several lines.
+    pub fn data(data: impl Into<String>) -> SseEvent {
+        SseEvent {
+            data: data.into(),
+            ..SseEvent::default()
+        }
+    }
+
+    /// Event carrying `data` serialised as JSON.
+    pub fn json<T: Serialize>(data: &T) -> anyhow::Result<SseEvent> {
+        let data = serde_json::to_string(data).with_context(|| "Error in serialising event data")?;
+
+        Ok(SseEvent::data(data))
+    }
+
+    /// Sets the event id, which the browser sends back as `Last-Event-ID` when it reconnects.
+    pub fn id(mut self, id: impl Into<String>) -> SseEvent {
+        self.id = Some(id.into());
+        self
+    }
+
+    /// Sets the event type, dispatched to `addEventListener(type, ..)` instead of `onmessage`.
+    pub fn event(mut self, event: impl Into<String>) -> SseEvent {
+        self.event = Some(event.into());
+        self
+    }
+
+    /// Sets how long the browser waits before reconnecting once the stream is closed.
+    pub fn retry(mut self, retry: Duration) -> SseEvent {
+        self.retry = Some(retry);
+        self
+    }
+
+    fn to_bytes(&self) -> Bytes {
+        let mut buffer = String::with_capacity(self.data.len() + 32);
+
+        // line breaks would end the field early, they have no meaning in ids and types anyway
+        if let Some(id) = &self.id {
+            let _ = writeln!(buffer, "id: {}", id.replace(['\r', '\n'], ""));
+        }
+        if let Some(event) = &self.event {
+            let _ = writeln!(buffer, "event: {}", event.replace(['\r', '\n'], ""));
+        }
+        if let Some(retry) = self.retry {
+            let _ = writeln!(buffer, "retry: {}", retry.as_millis());
+        }
+        for line in data_lines(&self.data) {
+            let _ = writeln!(buffer, "data: {}", line);
+        }
+        buffer.push('\n');
+
+        Bytes::from(buffer)
+    }
+}
+
+// SSE parsers end a line on `\r\n`, `\r` and `\n` alike, so that a lone `\r` must start a new `data` field too
+fn data_lines(data: &str) -> impl Iterator<Item=&str> {
+    data.split('\n')
+        .map(|line| line.strip_suffix('\r').unwrap_or(line))
+        .flat_map(|line| line.split('\r'))
+}
+
+const KEEP_ALIVE_COMMENT: &[u8] = b":\n\n";
+
+type SseError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Encodes `events`, interleaving a keep-alive comment whenever no event was sent for `keep_alive_interval`.
+pub(crate) fn event_stream<S, E>(events: S, keep_alive_interval: Option<Duration>) -> impl Stream<Item=Result<Bytes, SseError>> + Send
+    where
+        S: Stream<Item=Result<SseEvent, E>> + Send + 'static,
+        E: Into<SseError> + 'static,
+{
+    let keep_alive = keep_alive_interval.map(|period| {
+        let mut interval = tokio::time::interval_at(Instant::now() + period, period);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        interval
+    });
+
+    let state = (Box::pin(events), keep_alive, OpenStream::new());
+    stream::unfold(state, |(mut events, mut keep_alive, open_stream)| async move {
+        let chunk = match &mut keep_alive {
+            Some(interval) => {
+                tokio::select! {
+                    event = events.next() => {
+                        interval.reset();
+                        event?.map(SseChunk::Event)
+                    }
+                    _ = interval.tick() => Ok(SseChunk::KeepAlive),
+                }
+            }
+            None => events.next().await?.map(SseChunk::Event),
+        };
+
+        let chunk = chunk.map_err(Into::into).map(|chunk| match chunk {
+            SseChunk::Event(event) => event.to_bytes(),
+            SseChunk::KeepAlive => Bytes::from_static(KEEP_ALIVE_COMMENT),
+        });
+
+        Some((chunk, (events, keep_alive, open_stream)))
+    })
+}
+
+enum SseChunk {
+    Event(SseEvent),
+    KeepAlive,
+}
+
+/// Counts the stream as open for as long as the response body is alive.
+struct OpenStream;
+
+impl OpenStream {
+    fn new() -> OpenStream {
+        #[cfg(feature = "metrics")]
+        METRICS_LOGGER.log_sse_stream_opened();
+
+        OpenStream
+    }
+}
+
+impl Drop for OpenStream {
+    fn drop(&mut self) {
+        #[cfg(feature = "metrics")]
+        METRICS_LOGGER.log_sse_stream_closed();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_data_on_every_line_break() {
+        let event = SseEvent::data("a\r\nb\rc\nd");
+        assert_eq!(event.to_bytes(), "data: a\ndata: b\ndata: c\ndata: d\n\n");
+
+        let event = SseEvent::data("x\revent: admin\rid: 9").event("tick");
+        assert_eq!(event.to_bytes(), "event: tick\ndata: x\ndata: event: admin\ndata: id: 9\n\n");
+    }
+
+    #[test]
+    fn keeps_empty_data_lines() {
+        assert_eq!(SseEvent::data("").to_bytes(), "data: \n\n");
+        assert_eq!(SseEvent::data("a\n").to_bytes(), "data: a\ndata: \n\n");
+    }
+}
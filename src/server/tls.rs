@@ -0,0 +1,203 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::task::{Context as TaskContext, Poll};
+use std::time::Duration;
+
+use anyhow::Context;
+use futures::stream::FuturesUnordered;
+use futures::StreamExt;
+use hyper::server::accept::Accept;
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time::Timeout;
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::{any_supported_type, CertifiedKey};
+use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+use super::config::HttpProtocol;
+use super::connection::{ConnActivity, TrackedConn};
+use super::http_server::RemoteAddr;
+
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// PEM encoded certificate chain and private key used for terminating TLS.
+///
+/// Both files are re-read when the process receives `SIGHUP`, so certificates can be rotated without a restart.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "settings", derive(serde::Deserialize))]
+pub struct TlsConfig {
+    pub cert_file: String,
+    pub key_file: String,
+}
+
+impl TlsConfig {
+    pub fn new(cert_file: &str, key_file: &str) -> TlsConfig {
+        TlsConfig {
+            cert_file: cert_file.to_string(),
+            key_file: key_file.to_string(),
+        }
+    }
+
+    pub(crate) fn build_acceptor(&self, protocol: HttpProtocol) -> anyhow::Result<TlsAcceptor> {
+        let resolver = Arc::new(ReloadableCertResolver::new(self.clone())?);
+        reload_on_sighup(resolver.clone())?;
+
+        let mut server_config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_cert_resolver(resolver);
+        server_config.alpn_protocols = protocol.alpn_protocols();
+
+        Ok(TlsAcceptor::from(Arc::new(server_config)))
+    }
+}
+
+struct ReloadableCertResolver {
+    tls_config: TlsConfig,
+    certified_key: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ReloadableCertResolver {
+    fn new(tls_config: TlsConfig) -> anyhow::Result<ReloadableCertResolver> {
+        let certified_key = load_certified_key(&tls_config)?;
+
+        Ok(ReloadableCertResolver {
+            tls_config,
+            certified_key: RwLock::new(Arc::new(certified_key)),
+        })
+    }
+
+    fn reload(&self) -> anyhow::Result<()> {
+        let certified_key = load_certified_key(&self.tls_config)?;
+
+        let mut write_guard = self.certified_key.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+        *write_guard = Arc::new(certified_key);
+
+        Ok(())
+    }
+}
+
+impl ResolvesServerCert for ReloadableCertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let read_guard = self.certified_key.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+        Some(read_guard.clone())
+    }
+}
+
+fn load_certified_key(tls_config: &TlsConfig) -> anyhow::Result<CertifiedKey> {
+    let cert_file = File::open(&tls_config.cert_file).with_context(|| format!("Error in opening cert file: {}", tls_config.cert_file))?;
+    let certs: Vec<Certificate> = rustls_pemfile::certs(&mut BufReader::new(cert_file))
+        .with_context(|| format!("Error in parsing cert file: {}", tls_config.cert_file))?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+
+    if certs.is_empty() {
+        anyhow::bail!("No certificates found in cert file: {}", tls_config.cert_file);
+    }
+
+    let key_file = File::open(&tls_config.key_file).with_context(|| format!("Error in opening key file: {}", tls_config.key_file))?;
+    let private_key = rustls_pemfile::read_all(&mut BufReader::new(key_file))
+        .with_context(|| format!("Error in parsing key file: {}", tls_config.key_file))?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(key) | rustls_pemfile::Item::RSAKey(key) | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .with_context(|| format!("No private key found in key file: {}", tls_config.key_file))?;
+
+    let signing_key = any_supported_type(&private_key).with_context(|| format!("Unsupported private key in key file: {}", tls_config.key_file))?;
+
+    Ok(CertifiedKey::new(certs, signing_key))
+}
+
+fn reload_on_sighup(resolver: Arc<ReloadableCertResolver>) -> anyhow::Result<()> {
+    let mut hangup = signal(SignalKind::hangup()).with_context(|| "Error in installing SIGHUP handler")?;
+
+    tokio::task::spawn(async move {
+        while hangup.recv().await.is_some() {
+            match resolver.reload() {
+                Ok(()) => info!("Reloaded TLS certificate from: {}", resolver.tls_config.cert_file),
+                Err(err) => error!("Error in reloading TLS certificate, keeping the previous one: {:?}", err),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Completes the TLS handshakes of accepted connections concurrently, so a slow client never blocks the accept loop.
+///
+/// Handshakes keep progressing while `incoming` waits for a free connection permit.
+pub(crate) struct TlsIncoming<I: Accept> {
+    incoming: I,
+    acceptor: TlsAcceptor,
+    handshakes: FuturesUnordered<Timeout<tokio_rustls::Accept<I::Conn>>>,
+    // the listener gave its last connection, the handshakes under way still complete
+    incoming_ended: bool,
+}
+
+impl<I: Accept> TlsIncoming<I> {
+    pub(crate) fn new(incoming: I, acceptor: TlsAcceptor) -> TlsIncoming<I> {
+        TlsIncoming {
+            incoming,
+            acceptor,
+            handshakes: FuturesUnordered::new(),
+            incoming_ended: false,
+        }
+    }
+}
+
+impl<I> Accept for TlsIncoming<I>
+    where
+        I: Accept + Unpin,
+        I::Conn: AsyncRead + AsyncWrite + Unpin,
+{
+    type Conn = TlsStream<I::Conn>;
+    type Error = I::Error;
+
+    fn poll_accept(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        let this = self.get_mut();
+
+        while !this.incoming_ended {
+            match Pin::new(&mut this.incoming).poll_accept(cx) {
+                Poll::Ready(Some(Ok(stream))) => {
+                    let handshake = tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, this.acceptor.accept(stream));
+                    this.handshakes.push(handshake);
+                }
+                Poll::Ready(Some(Err(err))) => return Poll::Ready(Some(Err(err))),
+                Poll::Ready(None) => this.incoming_ended = true,
+                Poll::Pending => break,
+            }
+        }
+
+        loop {
+            match this.handshakes.poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(Ok(stream)))) => return Poll::Ready(Some(Ok(stream))),
+                Poll::Ready(Some(Ok(Err(err)))) => debug!("TLS handshake failed: {}", err),
+                Poll::Ready(Some(Err(_elapsed))) => debug!("TLS handshake timed out"),
+                Poll::Ready(None) if this.incoming_ended => return Poll::Ready(None),
+                Poll::Ready(None) | Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl<C: RemoteAddr> RemoteAddr for TlsStream<C> {
+    fn remote_addr(&self) -> SocketAddr {
+        self.get_ref().0.remote_addr()
+    }
+}
+
+impl<C: TrackedConn> TrackedConn for TlsStream<C> {
+    fn activity(&self) -> Arc<ConnActivity> {
+        self.get_ref().0.activity()
+    }
+}
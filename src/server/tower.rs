@@ -0,0 +1,215 @@
+use std::convert::Infallible;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::task::{Context as TaskContext, Poll};
+
+use anyhow::Context;
+use bytes::Buf;
+use futures::future::{self, BoxFuture};
+use futures::stream;
+use http::{HeaderValue, Request, Response, Uri};
+use hyper::body::HttpBody;
+use hyper::Body;
+
+use super::http_server::{install_panic_hook, route_handler};
+use super::request_context::REQUEST_ID_HEADER;
+use super::{ApiError, HttpResponse, HttpResult, HttpRoute, ServerConfig, Service, UNMATCHED_METRIC_PATH};
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Serves requests with a `Service` as `start_http_server` does, built-in routes included, but as a
+/// `tower::Service`, e.g. to nest an app in an axum router or wrap it with tower-http layers.
+///
+/// The client address is read from a `SocketAddr` request extension, and is the unspecified address without one.
+pub struct TowerAdapter<App> {
+    app: Arc<App>,
+    config: Arc<ServerConfig>,
+}
+
+impl<App> TowerAdapter<App>
+    where
+        App: 'static + Service,
+{
+    pub fn new<Config>(app: Arc<App>, config: Config) -> anyhow::Result<TowerAdapter<App>>
+        where
+            Config: TryInto<ServerConfig>,
+            Config::Error: Into<anyhow::Error>,
+    {
+        let config = config
+            .try_into()
+            .map_err(Into::into)
+            .with_context(|| "Error in loading server config")?;
+        config.validate().with_context(|| "Error in validating server config")?;
+        install_panic_hook();
+
+        Ok(TowerAdapter {
+            app,
+            config: Arc::new(config),
+        })
+    }
+}
+
+impl<App> Clone for TowerAdapter<App> {
+    fn clone(&self) -> Self {
+        TowerAdapter {
+            app: self.app.clone(),
+            config: self.config.clone(),
+        }
+    }
+}
+
+impl<App> tower_service::Service<Request<Body>> for TowerAdapter<App>
+    where
+        App: 'static + Service,
+{
+    type Response = Response<Body>;
+    type Error = Infallible;
+    type Future = BoxFuture<'static, Result<Response<Body>, Infallible>>;
+
+    fn poll_ready(&mut self, _cx: &mut TaskContext<'_>) -> Poll<Result<(), Infallible>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let remote_addr = req
+            .extensions()
+            .get::<SocketAddr>()
+            .copied()
+            .unwrap_or_else(|| SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)));
+        let response = route_handler(req, remote_addr, self.app.clone(), self.config.clone());
+
+        Box::pin(async move {
+            // tower services answer every request, errors included
+            let response = response.await.unwrap_or_else(ApiError::into_response);
+
+            Ok(response)
+        })
+    }
+}
+
+/// Mounts a `tower::Service` under a path prefix, e.g. `/api/legacy`, answering requests under it with the prefix
+/// stripped from their path:
+///
+/// ```ignore
+/// ["legacy", ..] => self.legacy.handle(body, route).await,
+/// ```
+///
+/// Metrics are recorded under the prefix. Response trailers are not forwarded. The service is cloned for every request,
+/// as tower services are, so it only needs to be `Send`, e.g. a `BoxCloneService`.
+pub struct TowerMount<S> {
+    prefix: &'static str,
+    // makes the mount `Sync` for any `Send` service, the lock being held only to clone it
+    service: Mutex<S>,
+}
+
+impl<S, ResBody> TowerMount<S>
+    where
+        S: tower_service::Service<Request<Body>, Response=Response<ResBody>> + Clone + Send + 'static,
+        S::Error: Into<BoxError>,
+        S::Future: Send,
+        ResBody: HttpBody + Send + 'static,
+        ResBody::Error: Into<BoxError>,
+{
+    pub fn new(prefix: &'static str, service: S) -> TowerMount<S> {
+        TowerMount {
+            prefix: prefix.trim_end_matches('/'),
+            service: Mutex::new(service),
+        }
+    }
+
+    /// Forwards the request to the mounted service, answering `404 Not Found` for paths outside the prefix.
+    pub async fn handle(&self, body: Body, route: &HttpRoute<'_>) -> HttpResult {
+        let path = match strip_prefix(route.path, self.prefix) {
+            Some(path) => path,
+            None => {
+                route.set_metric_path(UNMATCHED_METRIC_PATH);
+                return HttpResponse::not_found(route.path);
+            }
+        };
+        route.set_metric_path(self.prefix);
+
+        let path_and_query = match route.query {
+            Some(query) => format!("{}?{}", path, query),
+            None => path.to_string(),
+        };
+        let uri = Uri::builder()
+            .path_and_query(path_and_query)
+            .build()
+            .with_context(|| "Error in building mounted service uri")?;
+
+        let mut request = Request::builder()
+            .method(route.method.clone())
+            .uri(uri)
+            .version(route.version)
+            .body(body)
+            .with_context(|| "Error in building mounted service request")?;
+        *request.headers_mut() = route.req.headers().clone();
+        // the mounted service sees the id this server answers with, generated or not
+        if let Ok(request_id) = HeaderValue::from_str(&route.request_id) {
+            request.headers_mut().insert(REQUEST_ID_HEADER, request_id);
+        }
+        request.extensions_mut().insert(route.remote_addr);
+
+        let mut service = self
+            .service
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone();
+        future::poll_fn(|cx| service.poll_ready(cx))
+            .await
+            .map_err(|err| ApiError::InternalServerError(anyhow::anyhow!(err.into())))?;
+        let response = service
+            .call(request)
+            .await
+            .map_err(|err| ApiError::InternalServerError(anyhow::anyhow!(err.into())))?;
+
+        let (parts, body) = response.into_parts();
+        Ok(Response::from_parts(parts, into_body(body)))
+    }
+}
+
+#[async_trait::async_trait]
+impl<S, ResBody> Service for TowerMount<S>
+    where
+        S: tower_service::Service<Request<Body>, Response=Response<ResBody>> + Clone + Send + 'static,
+        S::Error: Into<BoxError>,
+        S::Future: Send,
+        ResBody: HttpBody + Send + 'static,
+        ResBody::Error: Into<BoxError>,
+{
+    async fn api_handler<'a>(
+        &'a self,
+        body: Body,
+        route: &HttpRoute<'a>,
+        _path: &[&str],
+    ) -> HttpResult {
+        self.handle(body, route).await
+    }
+}
+
+// `/api/legacy/users` under `/api/legacy` is `/users`, and `/api/legacy` itself is `/`
+fn strip_prefix<'p>(path: &'p str, prefix: &str) -> Option<&'p str> {
+    match path.strip_prefix(prefix)? {
+        "" => Some("/"),
+        rest if rest.starts_with('/') => Some(rest),
+        _ => None,
+    }
+}
+
+fn into_body<B>(body: B) -> Body
+    where
+        B: HttpBody + Send + 'static,
+        B::Error: Into<BoxError>,
+{
+    let chunks = stream::unfold(Box::pin(body), |mut body| async move {
+        let chunk = body
+            .data()
+            .await?
+            .map(|mut data| data.copy_to_bytes(data.remaining()))
+            .map_err(Into::<BoxError>::into);
+
+        Some((chunk, body))
+    });
+
+    Body::wrap_stream(chunks)
+}
@@ -8,9 +8,14 @@ pub fn load_config(config_dir: &str, env: &str) -> anyhow::Result<()> {
     return Ok(());
 }
 
+/// Initialises log4rs from `log4rs_file`, its `pattern` encoders prefixing records logged while serving a request with
+/// the request id.
 #[cfg(feature = "access_log")]
 pub fn setup_logging(log4rs_file: &str) -> anyhow::Result<()> {
-    log4rs::init_file(std::path::Path::new(log4rs_file), Default::default())
+    let mut deserializers = log4rs::config::Deserializers::default();
+    deserializers.insert("pattern", crate::server::logger::RequestIdEncoderDeserializer);
+
+    log4rs::init_file(std::path::Path::new(log4rs_file), deserializers)
         .with_context(|| format!("Error in opening log file: {}", log4rs_file))?;
 
     return Ok(());
@@ -0,0 +1,142 @@
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+use anyhow::Context;
+use http::{header, HeaderMap, Method, Response, StatusCode, Version};
+use hyper::upgrade::{OnUpgrade, Upgraded};
+use hyper::Body;
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::{Role, WebSocketConfig as ProtocolConfig};
+use tokio_tungstenite::WebSocketStream;
+
+pub use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+pub use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+pub use tokio_tungstenite::tungstenite::{Error as WebSocketError, Message};
+
+use super::commons::get_hostname_header;
+use super::connection::ConnActivity;
+#[cfg(feature = "metrics")]
+use super::logger::METRICS_LOGGER;
+use super::permessage_deflate::{DeflateParams, DeflateStream};
+use super::{ApiError, HttpResult, HttpRoute};
+
+/// An upgraded connection, both a `Stream` of incoming `Message`s and a `Sink` for outgoing ones. Use `StreamExt::split`
+/// to read and write from separate tasks.
+///
+/// Pings are answered with pongs as the socket is read. Messages over `max_message_size` end the stream with
+/// `WebSocketError::Capacity`, or `WebSocketError::Io` for compressed ones, after which the handler should close the
+/// socket with `CloseCode::Size`.
+pub type WebSocket = WebSocketStream<DeflateStream<Upgraded>>;
+
+const WEBSOCKET_VERSION: &str = "13";
+
+/// Holds the connection's upgrade until a handler takes it over, only set for WebSocket upgrade requests.
+pub(crate) type PendingUpgrade = Mutex<Option<OnUpgrade>>;
+
+pub(crate) fn is_upgrade_request(headers: &HeaderMap) -> bool {
+    has_token(headers, header::CONNECTION, "upgrade") && has_token(headers, header::UPGRADE, "websocket")
+}
+
+fn has_token(headers: &HeaderMap, name: header::HeaderName, token: &str) -> bool {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|value| value.trim().eq_ignore_ascii_case(token))
+}
+
+/// Answers a WebSocket handshake (RFC 6455, section 4.2) and hands the upgraded connection to `handler` on a task of its
+/// own. The `permessage-deflate` extension is negotiated when enabled in `ServerConfig::websocket`.
+pub(crate) fn upgrade<F, Fut>(route: &HttpRoute<'_>, handler: F) -> HttpResult
+    where
+        F: FnOnce(WebSocket) -> Fut + Send + 'static,
+        Fut: Future<Output=()> + Send + 'static,
+{
+    let headers = route.req.headers();
+    if route.method != Method::GET || route.version != Version::HTTP_11 || !is_upgrade_request(headers) {
+        return Err(ApiError::BadRequest(anyhow::anyhow!("Not a WebSocket upgrade request")));
+    }
+
+    if headers.get(header::SEC_WEBSOCKET_VERSION).map(|version| version.as_bytes()) != Some(WEBSOCKET_VERSION.as_bytes()) {
+        return Err(ApiError::BadRequest(anyhow::anyhow!("Unsupported Sec-WebSocket-Version, expected: {}", WEBSOCKET_VERSION)));
+    }
+
+    let key = headers
+        .get(header::SEC_WEBSOCKET_KEY)
+        .with_context(|| "Missing Sec-WebSocket-Key")?;
+    let accept = derive_accept_key(key.as_bytes());
+
+    let on_upgrade = route
+        .upgrade
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .take()
+        .with_context(|| "Connection already upgraded")?;
+
+    // upgraded connections carry no request, keep them from being closed as idle
+    let in_flight = route.req.extensions().get::<Arc<ConnActivity>>().map(|activity| activity.start_request());
+
+    let websocket_config = &route.config.websocket;
+    let deflate_params = websocket_config
+        .permessage_deflate
+        .then(|| DeflateParams::negotiate(headers))
+        .flatten();
+    let (max_message_size, max_frame_size) = (websocket_config.max_message_size, websocket_config.max_frame_size);
+    let protocol_config = ProtocolConfig::default()
+        .max_message_size(websocket_config.max_message_size)
+        .max_frame_size(websocket_config.max_frame_size);
+
+    let extensions = deflate_params.as_ref().map(DeflateParams::to_header);
+
+    tokio::task::spawn(async move {
+        match on_upgrade.await {
+            Ok(upgraded) => {
+                let _open_socket = OpenSocket::new();
+                let _in_flight = in_flight;
+
+                let stream = DeflateStream::new(upgraded, deflate_params, max_message_size, max_frame_size);
+                let socket = WebSocketStream::from_raw_socket(stream, Role::Server, Some(protocol_config)).await;
+                handler(socket).await;
+            }
+            Err(err) => warn!("Error in upgrading connection to WebSocket: {}", err),
+        }
+    });
+
+    let mut response = Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header(header::CONNECTION, "Upgrade")
+        .header(header::UPGRADE, "websocket")
+        .header(header::SEC_WEBSOCKET_ACCEPT, accept)
+        .header(header::HOST, get_hostname_header().clone());
+    if let Some(extensions) = extensions {
+        response = response.header(header::SEC_WEBSOCKET_EXTENSIONS, extensions);
+    }
+
+    let response = response
+        .body(Body::empty())
+        .with_context(|| "Error in building HttpResponse")?;
+
+    Ok(response)
+}
+
+/// Counts the socket as open until its handler returns.
+struct OpenSocket;
+
+impl OpenSocket {
+    fn new() -> OpenSocket {
+        #[cfg(feature = "metrics")]
+        METRICS_LOGGER.log_websocket_opened();
+
+        OpenSocket
+    }
+}
+
+impl Drop for OpenSocket {
+    fn drop(&mut self) {
+        #[cfg(feature = "metrics")]
+        METRICS_LOGGER.log_websocket_closed();
+    }
+}